@@ -1,172 +1,839 @@
-//! Simple ANSI escape sequence filter.
+//! ANSI escape sequence processing.
 //!
-//! This module provides basic ANSI sequence filtering to extract
-//! plain text from shell output without full parser complexity.
-
-/// State machine for filtering ANSI escape sequences.
-#[derive(Debug, Clone, Copy, PartialEq)]
-enum FilterState {
-    /// Normal text output
-    Normal,
-    /// ESC character received, expecting '['
-    Escape,
-    /// Inside CSI sequence, reading parameters and final byte
-    Csi,
-}
+//! This module feeds shell output through the `vte` parser and turns
+//! the resulting control functions into operations on the terminal
+//! model in `term.rs`.
+
+use crate::grid::{ClearMode, Color};
+use crate::term::{Attr, Terminal};
+use vte::{Params, ParamsIter, Parser, Perform};
 
-/// ANSI escape sequence filter.
+/// Escape sequence processor.
 ///
-/// Processes shell output byte-by-byte, filtering out ANSI escape
-/// sequences while preserving plain text.
-pub struct AnsiFilter {
-    state: FilterState,
+/// Keeps the parser state between calls, so escape sequences and UTF-8
+/// characters split across several PTY reads are handled correctly.
+/// Invalid UTF-8 is drawn as U+FFFD.
+pub struct Processor {
+    parser: Parser,
 }
 
-impl AnsiFilter {
-    /// Create a new ANSI filter.
+impl Processor {
+    /// Create a new processor.
     pub fn new() -> Self {
         Self {
-            state: FilterState::Normal,
+            parser: Parser::new(),
         }
     }
 
-    /// Process a single byte, returning Some(char) if it should be displayed.
-    ///
-    /// # Returns
-    /// - `Some(char)` - Printable character that should be displayed
-    /// - `None` - Byte was filtered out (part of ANSI sequence or control char)
-    pub fn process(&mut self, byte: u8) -> Option<char> {
-        match self.state {
-            FilterState::Normal => {
-                match byte {
-                    0x1b => {
-                        // ESC - start of escape sequence
-                        self.state = FilterState::Escape;
-                        None
-                    }
-                    0x07 => {
-                        // BEL - bell character, ignore
-                        None
-                    }
-                    0x08 => {
-                        // BS - backspace, return as control char
-                        Some('\x08')
-                    }
-                    0x09 => {
-                        // HT - tab, return as control char
-                        Some('\t')
-                    }
-                    0x0a..=0x0d => {
-                        // LF, VT, FF, CR - line breaks, return as control chars
-                        Some(byte as char)
-                    }
-                    0x20..=0x7e => {
-                        // Printable ASCII range
-                        Some(byte as char)
-                    }
-                    _ => {
-                        // Other control characters, ignore
-                        None
-                    }
+    /// Process raw PTY output, applying it to the terminal.
+    pub fn advance(&mut self, terminal: &mut Terminal, bytes: &[u8]) {
+        let mut performer = Performer { terminal };
+        self.parser.advance(&mut performer, bytes);
+    }
+}
+
+impl Default for Processor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `vte::Perform` implementation dispatching to a `Terminal`.
+struct Performer<'a> {
+    terminal: &'a mut Terminal,
+}
+
+/// Get the first value of the parameter at `index`, or `default` if it
+/// is missing or zero.
+fn param_or(params: &Params, index: usize, default: u16) -> u16 {
+    match params.iter().nth(index).map(|param| param[0]) {
+        Some(0) | None => default,
+        Some(value) => value,
+    }
+}
+
+/// Map the parameter of ED/EL to the region it clears.
+fn clear_mode(params: &Params) -> Option<ClearMode> {
+    match params.iter().next().map(|param| param[0]).unwrap_or(0) {
+        0 => Some(ClearMode::Below),
+        1 => Some(ClearMode::Above),
+        2 => Some(ClearMode::All),
+        _ => None,
+    }
+}
+
+/// Map an SGR parameter to the attribute it selects.
+///
+/// Extended colors (38, 48, 58) may take further parameters from `rest`,
+/// either as `:` sub-parameters of `param` or as following `;` separated
+/// parameters.
+fn sgr_attr(param: &[u16], rest: &mut ParamsIter) -> Option<Attr> {
+    let attr = match param {
+        [0] => Attr::Reset,
+        [1] => Attr::Bold,
+        [2] => Attr::Dim,
+        [3] => Attr::Italic,
+        // `4:0` is "no underline"; other styles (curly, dotted, ...) are
+        // drawn as a plain underline
+        [4, 0] => Attr::CancelUnderline,
+        [4, ..] => Attr::Underline,
+        [7] => Attr::Inverse,
+        [8] => Attr::Hidden,
+        [9] => Attr::Strikethrough,
+        [21] | [22] => Attr::CancelBoldDim,
+        [23] => Attr::CancelItalic,
+        [24] => Attr::CancelUnderline,
+        [27] => Attr::CancelInverse,
+        [28] => Attr::CancelHidden,
+        [29] => Attr::CancelStrikethrough,
+        [n @ 30..=37] => Attr::Foreground(Color::Indexed((n - 30) as u8)),
+        [38, sub @ ..] => Attr::Foreground(extended_color(sub, rest)?),
+        [39] => Attr::Foreground(Color::Default),
+        [n @ 40..=47] => Attr::Background(Color::Indexed((n - 40) as u8)),
+        [48, sub @ ..] => Attr::Background(extended_color(sub, rest)?),
+        [49] => Attr::Background(Color::Default),
+        [58, sub @ ..] => {
+            // Underline color is not supported, but its parameters must
+            // not be mistaken for attributes
+            extended_color(sub, rest);
+            return None;
+        }
+        [n @ 90..=97] => Attr::Foreground(Color::Indexed((n - 90 + 8) as u8)),
+        [n @ 100..=107] => Attr::Background(Color::Indexed((n - 100 + 8) as u8)),
+        _ => return None,
+    };
+    Some(attr)
+}
+
+/// Parse the color selector following SGR 38/48/58.
+///
+/// Accepts `5;n` / `2;r;g;b` as separate parameters and `5:n` /
+/// `2:r:g:b` / `2:colorspace:r:g:b` as sub-parameters.
+fn extended_color(sub: &[u16], rest: &mut ParamsIter) -> Option<Color> {
+    let component = |value: u16| u8::try_from(value).ok();
+
+    if !sub.is_empty() {
+        return match sub {
+            [5, index, ..] => Some(Color::Indexed(component(*index)?)),
+            [2, r, g, b] => Some(Color::Rgb(component(*r)?, component(*g)?, component(*b)?)),
+            [2, _, r, g, b, ..] => Some(Color::Rgb(component(*r)?, component(*g)?, component(*b)?)),
+            _ => None,
+        };
+    }
+
+    match rest.next()? {
+        [5] => Some(Color::Indexed(component(rest.next()?[0])?)),
+        [2] => {
+            let r = rest.next()?[0];
+            let g = rest.next()?[0];
+            let b = rest.next()?[0];
+            Some(Color::Rgb(component(r)?, component(g)?, component(b)?))
+        }
+        _ => None,
+    }
+}
+
+impl Perform for Performer<'_> {
+    fn print(&mut self, c: char) {
+        self.terminal.print(c);
+    }
+
+    fn execute(&mut self, byte: u8) {
+        match byte {
+            0x07 => self.terminal.bell(),
+            0x08 => self.terminal.backspace(),
+            0x09 => self.terminal.tab(1),
+            0x0a..=0x0c => self.terminal.linefeed(),
+            0x0d => self.terminal.carriage_return(),
+            // C1 equivalents of ESC D, ESC E, ESC H, ESC M
+            0x84 => self.terminal.index(),
+            0x85 => {
+                self.terminal.carriage_return();
+                self.terminal.index();
+            }
+            0x88 => self.terminal.set_tab_stop(),
+            0x8d => self.terminal.reverse_index(),
+            _ => log::trace!("Unhandled control byte 0x{:02x}", byte),
+        }
+    }
+
+    fn osc_dispatch(&mut self, params: &[&[u8]], _bell_terminated: bool) {
+        match params {
+            // Set icon name and window title / set window title
+            [b"0" | b"2", title, ..] => {
+                self.terminal.set_title(&String::from_utf8_lossy(title));
+            }
+            _ => log::trace!("Unhandled OSC sequence with {} params", params.len()),
+        }
+    }
+
+    fn csi_dispatch(&mut self, params: &Params, intermediates: &[u8], ignore: bool, action: char) {
+        if ignore {
+            return;
+        }
+
+        let term = &mut *self.terminal;
+        let count = |index| param_or(params, index, 1) as usize;
+
+        match (intermediates, action) {
+            ([], '@') => term.insert_blank(count(0)),
+            ([], 'A') => term.move_up(count(0)),
+            ([], 'B' | 'e') => term.move_down(count(0)),
+            ([], 'C' | 'a') => term.move_forward(count(0)),
+            ([], 'D') => term.move_backward(count(0)),
+            ([], 'E') => {
+                term.move_down(count(0));
+                term.carriage_return();
+            }
+            ([], 'F') => {
+                term.move_up(count(0));
+                term.carriage_return();
+            }
+            ([], 'G' | '`') => term.goto_col(count(0) - 1),
+            ([], 'H' | 'f') => term.goto(count(0) - 1, count(1) - 1),
+            ([], 'I') => term.tab(count(0)),
+            ([], 'J') if param_or(params, 0, 0) == 3 => term.clear_history(),
+            ([], 'J') => {
+                if let Some(mode) = clear_mode(params) {
+                    term.erase_in_display(mode);
                 }
             }
-            FilterState::Escape => {
-                match byte {
-                    b'[' => {
-                        // CSI sequence start
-                        self.state = FilterState::Csi;
-                        None
-                    }
-                    b'P' | b']' | b'^' | b'_' => {
-                        // Other escape sequences (DCS, OSC, PM, APC)
-                        // Ignore for now
-                        None
-                    }
-                    _ => {
-                        // Simple escape sequence like ESC M, return to normal
-                        self.state = FilterState::Normal;
-                        None
-                    }
+            ([], 'K') => {
+                if let Some(mode) = clear_mode(params) {
+                    term.erase_in_line(mode);
                 }
             }
-            FilterState::Csi => {
-                // CSI parameter byte or final byte
-                // Parameters: 0x30-0x3F (0-9, :, ;, <, =, >, ?)
-                // Intermediate: 0x20-0x2F (space, !"#$%&'()*+,-./)
-                // Final: 0x40-0x7E (@A-Z[\]^_`a-z{|}~)
-                match byte {
-                    0x40..=0x7e => {
-                        // Final byte, sequence complete
-                        self.state = FilterState::Normal;
-                        None
-                    }
-                    _ => {
-                        // Parameter or intermediate byte, stay in CSI
-                        None
+            ([], 'L') => term.insert_lines(count(0)),
+            ([], 'M') => term.delete_lines(count(0)),
+            ([], 'P') => term.delete_chars(count(0)),
+            ([], 'S') => term.scroll_up(count(0)),
+            ([], 'T') => term.scroll_down(count(0)),
+            ([], 'X') => term.erase_chars(count(0)),
+            ([], 'Z') => term.back_tab(count(0)),
+            ([], 'c') if param_or(params, 0, 0) == 0 => term.identify_terminal(),
+            ([], 'd') => term.goto_row(count(0) - 1),
+            ([], 'g') => match param_or(params, 0, 0) {
+                0 => term.clear_tab_stops(false),
+                3 => term.clear_tab_stops(true),
+                _ => {}
+            },
+            ([], 'h' | 'l') => {
+                for param in params.iter() {
+                    term.set_mode(param[0], action == 'h');
+                }
+            }
+            ([b'?'], 'h' | 'l') => {
+                for param in params.iter() {
+                    term.set_private_mode(param[0], action == 'h');
+                }
+            }
+            ([], 'm') => {
+                if params.is_empty() {
+                    term.set_attribute(Attr::Reset);
+                }
+                let mut iter = params.iter();
+                while let Some(param) = iter.next() {
+                    match sgr_attr(param, &mut iter) {
+                        Some(attr) => term.set_attribute(attr),
+                        None => log::trace!("Unhandled SGR parameter {:?}", param),
                     }
                 }
             }
-        }
-    }
-
-    /// Process a byte slice, returning a String of filtered text.
-    pub fn process_slice(&mut self, bytes: &[u8]) -> String {
-        let mut result = String::new();
-        for &byte in bytes {
-            if let Some(c) = self.process(byte) {
-                result.push(c);
+            ([b'>'], 'm') if param_or(params, 0, 0) == 4 => {
+                term.set_modify_other_keys(param_or(params, 1, 0));
+            }
+            ([b'>'], 'n') if param_or(params, 0, 0) == 4 => term.set_modify_other_keys(0),
+            ([], 'n') => term.device_status(param_or(params, 0, 0)),
+            ([b' '], 'q') => term.set_cursor_style(param_or(params, 0, 0)),
+            ([], 'r') => {
+                let bottom = match param_or(params, 1, 0) {
+                    0 => None,
+                    bottom => Some(bottom as usize - 1),
+                };
+                term.set_scroll_region(count(0) - 1, bottom);
             }
+            ([], 's') => term.save_cursor(),
+            ([], 'u') => term.restore_cursor(),
+            ([b'>'], 'u') => term.push_keyboard_mode(param_or(params, 0, 0)),
+            ([b'<'], 'u') => term.pop_keyboard_modes(count(0)),
+            ([b'='], 'u') => term.set_keyboard_mode(param_or(params, 0, 0), param_or(params, 1, 1)),
+            ([b'?'], 'u') => term.report_keyboard_mode(),
+            _ => log::trace!(
+                "Unhandled CSI sequence: intermediates={:?} action={}",
+                intermediates,
+                action
+            ),
         }
-        result
     }
 
-    /// Reset the filter to initial state.
-    pub fn reset(&mut self) {
-        self.state = FilterState::Normal;
-    }
-}
+    fn esc_dispatch(&mut self, intermediates: &[u8], ignore: bool, byte: u8) {
+        if ignore {
+            return;
+        }
 
-impl Default for AnsiFilter {
-    fn default() -> Self {
-        Self::new()
+        match (intermediates, byte) {
+            ([], b'7') => self.terminal.save_cursor(),
+            ([], b'8') => self.terminal.restore_cursor(),
+            ([], b'D') => self.terminal.index(),
+            ([], b'E') => {
+                self.terminal.carriage_return();
+                self.terminal.index();
+            }
+            ([], b'H') => self.terminal.set_tab_stop(),
+            ([], b'M') => self.terminal.reverse_index(),
+            ([], b'c') => self.terminal.reset(),
+            ([], b'=') => self.terminal.set_keypad_application_mode(true),
+            ([], b'>') => self.terminal.set_keypad_application_mode(false),
+            _ => log::trace!(
+                "Unhandled ESC sequence: intermediates={:?} byte={}",
+                intermediates,
+                byte as char
+            ),
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::grid::Flags;
+    use crate::input::InputMode;
+    use crate::mouse::{MouseEncoding, MouseMode, MouseProtocol};
+    use crate::term::{CursorShape, CursorStyle};
+
+    /// Get the screen lines of a terminal as plain text.
+    fn lines(terminal: &Terminal) -> Vec<String> {
+        terminal
+            .grid()
+            .to_string()
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+
+    /// Run `input` through a fresh 5x10 terminal and return its lines.
+    fn render(input: &[u8]) -> Vec<String> {
+        let mut terminal = Terminal::new(5, 10, 100);
+        let mut processor = Processor::new();
+        processor.advance(&mut terminal, input);
+        lines(&terminal)
+    }
 
     #[test]
     fn test_plain_text() {
-        let mut filter = AnsiFilter::new();
-        let input = b"Hello, World!";
-        let output = filter.process_slice(input);
-        assert_eq!(output, "Hello, World!");
+        let lines = render(b"Hello");
+        assert_eq!(lines[0], "Hello");
     }
 
     #[test]
-    fn test_color_filter() {
-        let mut filter = AnsiFilter::new();
-        // Red color sequence: ESC[31m
-        let input = b"\x1b[31mHello\x1b[0m";
-        let output = filter.process_slice(input);
-        assert_eq!(output, "Hello");
+    fn test_color_sequences_are_consumed() {
+        let lines = render(b"\x1b[31mHello\x1b[0m");
+        assert_eq!(lines[0], "Hello");
     }
 
     #[test]
-    fn test_cursor_movement() {
-        let mut filter = AnsiFilter::new();
-        // Cursor up: ESC[A
-        let input = b"Text\x1b[A_more";
-        let output = filter.process_slice(input);
-        assert_eq!(output, "Text_more");
+    fn test_line_breaks() {
+        let lines = render(b"Line1\r\nLine2\rX");
+        assert_eq!(lines[0], "Line1");
+        assert_eq!(lines[1], "Xine2");
     }
 
     #[test]
-    fn test_line_breaks() {
-        let mut filter = AnsiFilter::new();
-        let input = b"Line1\nLine2\rLine3";
-        let output = filter.process_slice(input);
-        assert_eq!(output, "Line1\nLine2\rLine3");
+    fn test_cursor_position() {
+        let lines = render(b"\x1b[3;4Hx\x1b[1;1Hy");
+        assert_eq!(lines[0], "y");
+        assert_eq!(lines[2], "   x");
+    }
+
+    #[test]
+    fn test_erase_in_line() {
+        let lines = render(b"abcdef\x1b[3G\x1b[K");
+        assert_eq!(lines[0], "ab");
+    }
+
+    #[test]
+    fn test_erase_in_display() {
+        let lines = render(b"one\r\ntwo\r\nthree\x1b[2;2H\x1b[J");
+        assert_eq!(lines, vec!["one", "t", "", "", ""]);
+    }
+
+    #[test]
+    fn test_autowrap() {
+        let lines = render(b"0123456789ab");
+        assert_eq!(lines[0], "0123456789");
+        assert_eq!(lines[1], "ab");
+    }
+
+    #[test]
+    fn test_scroll_at_bottom() {
+        let lines = render(b"1\r\n2\r\n3\r\n4\r\n5\r\n6");
+        assert_eq!(lines, vec!["2", "3", "4", "5", "6"]);
+    }
+
+    #[test]
+    fn test_scroll_region() {
+        let lines = render(b"a\r\nb\r\nc\r\nd\r\ne\x1b[2;4r\x1b[4;1H\n");
+        assert_eq!(lines, vec!["a", "c", "d", "", "e"]);
+    }
+
+    #[test]
+    fn test_insert_and_delete_chars() {
+        let lines = render(b"abcdef\x1b[1;2H\x1b[2@\x1b[1;5H\x1b[P");
+        assert_eq!(lines[0], "a  bdef");
+    }
+
+    #[test]
+    fn test_insert_and_delete_lines() {
+        let lines = render(b"a\r\nb\r\nc\x1b[2;1H\x1b[L");
+        assert_eq!(lines, vec!["a", "", "b", "c", ""]);
+
+        let lines = render(b"a\r\nb\r\nc\x1b[1;1H\x1b[2M");
+        assert_eq!(lines, vec!["c", "", "", "", ""]);
+    }
+
+    #[test]
+    fn test_cursor_position_report() {
+        let mut terminal = Terminal::new(5, 10, 100);
+        let mut processor = Processor::new();
+        processor.advance(&mut terminal, b"\x1b[2;3H\x1b[6n");
+        assert_eq!(terminal.take_responses(), b"\x1b[2;3R");
+    }
+
+    #[test]
+    fn test_cursor_position_report_above_origin() {
+        // Restoring a cursor saved above the scroll region leaves it
+        // outside the region in origin mode
+        let mut terminal = Terminal::new(5, 10, 100);
+        let mut processor = Processor::new();
+        processor.advance(&mut terminal, b"\x1b7\x1b[3;5r\x1b[?6h\x1b8\x1b[6n");
+        assert_eq!(terminal.take_responses(), b"\x1b[1;1R");
+    }
+
+    #[test]
+    fn test_sequence_split_across_reads() {
+        let mut terminal = Terminal::new(5, 10, 100);
+        let mut processor = Processor::new();
+        processor.advance(&mut terminal, b"ab\x1b[");
+        processor.advance(&mut terminal, b"1Gc");
+        assert_eq!(lines(&terminal)[0], "cb");
+    }
+
+    #[test]
+    fn test_title() {
+        let mut terminal = Terminal::new(5, 10, 100);
+        let mut processor = Processor::new();
+        processor.advance(&mut terminal, b"\x1b]0;my title\x07");
+        assert_eq!(terminal.title(), "my title");
+    }
+
+    #[test]
+    fn test_sgr_flags() {
+        let mut terminal = Terminal::new(5, 10, 100);
+        let mut processor = Processor::new();
+        processor.advance(&mut terminal, b"\x1b[1;4ma\x1b[22mb\x1b[0mc");
+        let row = terminal.grid().visible_row(0);
+        assert!(row[0].flags.contains(Flags::BOLD | Flags::UNDERLINE));
+        assert!(!row[1].flags.contains(Flags::BOLD));
+        assert!(row[1].flags.contains(Flags::UNDERLINE));
+        assert_eq!(row[2].flags, Flags::default());
+    }
+
+    #[test]
+    fn test_wide_chars() {
+        let mut terminal = Terminal::new(5, 10, 100);
+        let mut processor = Processor::new();
+        processor.advance(&mut terminal, "a\u{4e2d}b".as_bytes());
+        let row = terminal.grid().visible_row(0);
+        assert!(row[1].flags.contains(Flags::WIDE_CHAR));
+        assert!(row[2].flags.contains(Flags::WIDE_CHAR_SPACER));
+        assert_eq!(row[3].c, 'b');
+        assert_eq!(lines(&terminal)[0], "a\u{4e2d}b");
+    }
+
+    #[test]
+    fn test_wide_char_wraps_at_line_end() {
+        let lines = render("012345678\u{4e2d}".as_bytes());
+        assert_eq!(lines[0], "012345678");
+        assert_eq!(lines[1], "\u{4e2d}");
+    }
+
+    #[test]
+    fn test_combining_marks() {
+        let mut terminal = Terminal::new(5, 10, 100);
+        let mut processor = Processor::new();
+        processor.advance(&mut terminal, "e\u{301}x".as_bytes());
+        let row = terminal.grid().visible_row(0);
+        assert_eq!(row[0].zerowidth, vec!['\u{301}']);
+        assert_eq!(row[1].c, 'x');
+    }
+
+    #[test]
+    fn test_overwriting_half_of_wide_char() {
+        let lines = render("\u{4e2d}\x1b[1;2Hx".as_bytes());
+        assert_eq!(lines[0], " x");
+    }
+
+    #[test]
+    fn test_utf8_split_across_reads() {
+        let text = "\u{e9}\u{4e2d}\u{1f600}\u{2502}";
+        let bytes = text.as_bytes();
+
+        for split in 0..=bytes.len() {
+            let mut terminal = Terminal::new(5, 10, 100);
+            let mut processor = Processor::new();
+            processor.advance(&mut terminal, &bytes[..split]);
+            processor.advance(&mut terminal, &bytes[split..]);
+            assert_eq!(lines(&terminal)[0], text, "split at {}", split);
+        }
+    }
+
+    #[test]
+    fn test_invalid_utf8_replaced() {
+        let lines = render(b"a\xffb");
+        assert_eq!(lines[0], "a\u{fffd}b");
+    }
+
+    /// Run `input` through a fresh terminal and return the first cell.
+    fn first_cell(input: &[u8]) -> crate::grid::Cell {
+        let mut terminal = Terminal::new(5, 10, 100);
+        let mut processor = Processor::new();
+        processor.advance(&mut terminal, input);
+        terminal.grid().visible_row(0)[0].clone()
+    }
+
+    #[test]
+    fn test_sgr_16_colors() {
+        let cell = first_cell(b"\x1b[31;42mx");
+        assert_eq!(cell.fg, Color::Indexed(1));
+        assert_eq!(cell.bg, Color::Indexed(2));
+
+        let cell = first_cell(b"\x1b[97;104mx");
+        assert_eq!(cell.fg, Color::Indexed(15));
+        assert_eq!(cell.bg, Color::Indexed(12));
+
+        let cell = first_cell(b"\x1b[31;42m\x1b[39;49mx");
+        assert_eq!(cell.fg, Color::Default);
+        assert_eq!(cell.bg, Color::Default);
+    }
+
+    #[test]
+    fn test_sgr_256_colors() {
+        let cell = first_cell(b"\x1b[38;5;196;48;5;21mx");
+        assert_eq!(cell.fg, Color::Indexed(196));
+        assert_eq!(cell.bg, Color::Indexed(21));
+
+        let cell = first_cell(b"\x1b[38:5:196mx");
+        assert_eq!(cell.fg, Color::Indexed(196));
+    }
+
+    #[test]
+    fn test_sgr_truecolor() {
+        let cell = first_cell(b"\x1b[38;2;10;20;30;48;2;40;50;60mx");
+        assert_eq!(cell.fg, Color::Rgb(10, 20, 30));
+        assert_eq!(cell.bg, Color::Rgb(40, 50, 60));
+
+        let cell = first_cell(b"\x1b[38:2:10:20:30mx");
+        assert_eq!(cell.fg, Color::Rgb(10, 20, 30));
+
+        let cell = first_cell(b"\x1b[48:2::10:20:30mx");
+        assert_eq!(cell.bg, Color::Rgb(10, 20, 30));
+    }
+
+    #[test]
+    fn test_sgr_extended_color_followed_by_attribute() {
+        let cell = first_cell(b"\x1b[38;5;1;1mx");
+        assert_eq!(cell.fg, Color::Indexed(1));
+        assert!(cell.flags.contains(Flags::BOLD));
+
+        // The 5 of an underline color must not be read as blink/attribute
+        let cell = first_cell(b"\x1b[58;2;1;2;3;7mx");
+        assert_eq!(cell.fg, Color::Default);
+        assert!(cell.flags.contains(Flags::INVERSE));
+    }
+
+    #[test]
+    fn test_sgr_hidden_and_reset() {
+        let cell = first_cell(b"\x1b[8;9mx");
+        assert!(cell.flags.contains(Flags::HIDDEN | Flags::STRIKETHROUGH));
+
+        let cell = first_cell(b"\x1b[8;31m\x1b[mx");
+        assert_eq!(cell.flags, Flags::default());
+        assert_eq!(cell.fg, Color::Default);
+    }
+
+    #[test]
+    fn test_erase_keeps_background_color() {
+        let mut terminal = Terminal::new(5, 10, 100);
+        let mut processor = Processor::new();
+        processor.advance(&mut terminal, b"\x1b[44m\x1b[2J");
+        assert_eq!(terminal.grid().visible_row(3)[5].bg, Color::Indexed(4));
+    }
+
+    #[test]
+    fn test_scrollback() {
+        let mut terminal = Terminal::new(5, 10, 100);
+        let mut processor = Processor::new();
+        processor.advance(&mut terminal, b"1\r\n2\r\n3\r\n4\r\n5\r\n6\r\n7");
+
+        terminal.scroll_display(10);
+        assert_eq!(terminal.grid().display_offset(), 2);
+        assert_eq!(terminal.grid().visible_row(0)[0].c, '1');
+        assert_eq!(terminal.grid().visible_row(4)[0].c, '5');
+
+        processor.advance(&mut terminal, b"\x1b[3J");
+        assert_eq!(terminal.grid().display_offset(), 0);
+        terminal.scroll_display(10);
+        assert_eq!(terminal.grid().display_offset(), 0);
+    }
+
+    #[test]
+    fn test_resize() {
+        let mut terminal = Terminal::new(5, 10, 100);
+        let mut processor = Processor::new();
+        processor.advance(&mut terminal, b"a\r\nb\r\nc\r\nd\r\ne\x1b[2;4r");
+        terminal.resize(3, 20);
+
+        // The scroll region is reset, so a linefeed at the bottom scrolls
+        // the whole screen
+        processor.advance(&mut terminal, b"\x1b[3;1H\nf");
+        assert_eq!(lines(&terminal), vec!["d", "e", "f"]);
+
+        processor.advance(&mut terminal, b"\x1b[1;15Hxyz");
+        assert_eq!(lines(&terminal)[0], "d             xyz");
+    }
+
+    #[test]
+    fn test_resize_reflows_wrapped_lines() {
+        let mut terminal = Terminal::new(5, 10, 100);
+        let mut processor = Processor::new();
+        processor.advance(&mut terminal, b"abcdefghijkl\r\n$ ");
+        terminal.resize(5, 6);
+        assert_eq!(lines(&terminal), vec!["abcdef", "ghijkl", "$", "", ""]);
+
+        terminal.resize(5, 20);
+        processor.advance(&mut terminal, b"ls");
+        assert_eq!(lines(&terminal), vec!["abcdefghijkl", "$ ls", "", "", ""]);
+    }
+
+    #[test]
+    fn test_resize_keeps_pending_wrap() {
+        let mut terminal = Terminal::new(5, 10, 100);
+        let mut processor = Processor::new();
+        processor.advance(&mut terminal, b"0123456789");
+        terminal.resize(5, 12);
+        processor.advance(&mut terminal, b"ab");
+        assert_eq!(lines(&terminal)[0], "0123456789ab");
+    }
+
+    #[test]
+    fn test_alternate_screen() {
+        let mut terminal = Terminal::new(3, 10, 100);
+        let mut processor = Processor::new();
+        processor.advance(&mut terminal, b"$ less\r\n\x1b[?1049h");
+        assert_eq!(lines(&terminal), vec!["", "", ""]);
+
+        processor.advance(&mut terminal, b"\x1b[Hpage 1\r\n\n\n\npage 2");
+        assert_eq!(lines(&terminal), vec!["", "", "page 2"]);
+        assert_eq!(terminal.grid().max_history(), 0);
+
+        // Leaving restores the primary screen and the cursor
+        processor.advance(&mut terminal, b"\x1b[?1049l$");
+        assert_eq!(lines(&terminal), vec!["$ less", "$", ""]);
+        terminal.scroll_display(isize::MAX);
+        assert_eq!(terminal.grid().display_offset(), 0);
+    }
+
+    #[test]
+    fn test_alternate_screen_1047() {
+        let mut terminal = Terminal::new(3, 10, 100);
+        let mut processor = Processor::new();
+        processor.advance(&mut terminal, b"main\x1b[?1047halt");
+        assert_eq!(lines(&terminal), vec!["    alt", "", ""]);
+
+        processor.advance(&mut terminal, b"\x1b[?1047l");
+        assert_eq!(lines(&terminal), vec!["main", "", ""]);
+
+        // The alternate screen was cleared when leaving it
+        processor.advance(&mut terminal, b"\x1b[?47h");
+        assert_eq!(lines(&terminal), vec!["", "", ""]);
+    }
+
+    #[test]
+    fn test_reset_modes() {
+        let mut terminal = Terminal::new(3, 10, 100);
+        let mut processor = Processor::new();
+        processor.advance(&mut terminal, b"$ vim\r\n");
+        processor.advance(
+            &mut terminal,
+            b"\x1b[?1049h\x1b[?1h\x1b[?1000h\x1b[?2004h\x1b[>1u\x1b[?25l\x1b[5 q\x1b[2;3r",
+        );
+        terminal.reset_modes();
+
+        // The primary screen is back, with the default modes
+        assert_eq!(lines(&terminal), vec!["$ vim", "", ""]);
+        assert_eq!(terminal.input_mode(), InputMode::default());
+        assert_eq!(terminal.mouse_protocol(), MouseProtocol::default());
+        assert!(!terminal.bracketed_paste());
+        assert!(terminal.cursor_visible());
+        assert_eq!(terminal.cursor_style(), None);
+        processor.advance(&mut terminal, b"\n\n\nx");
+        assert_eq!(lines(&terminal), vec!["", "", "x"]);
+    }
+
+    #[test]
+    fn test_reset_on_alternate_screen() {
+        let mut terminal = Terminal::new(3, 10, 100);
+        let mut processor = Processor::new();
+        processor.advance(&mut terminal, b"\x1b[?1049hvim\x1bc");
+        assert_eq!(lines(&terminal), vec!["", "", ""]);
+
+        // The reset returns to the primary screen with its scrollback
+        assert_eq!(terminal.grid().max_history(), 100);
+        processor.advance(&mut terminal, b"1\r\n2\r\n3\r\n4");
+        terminal.scroll_display(isize::MAX);
+        assert_eq!(terminal.grid().display_offset(), 1);
+    }
+
+    #[test]
+    fn test_input_modes() {
+        let mut terminal = Terminal::new(5, 10, 100);
+        let mut processor = Processor::new();
+        assert_eq!(terminal.input_mode(), InputMode::default());
+
+        processor.advance(&mut terminal, b"\x1b[?1h\x1b=\x1b[20h");
+        let mode = terminal.input_mode();
+        assert!(mode.app_cursor && mode.app_keypad && mode.linefeed_newline);
+
+        processor.advance(&mut terminal, b"\x1b[?1l\x1b>\x1b[20l");
+        assert_eq!(terminal.input_mode(), InputMode::default());
+    }
+
+    #[test]
+    fn test_kitty_keyboard_mode_stack() {
+        let mut terminal = Terminal::new(5, 10, 100);
+        let mut processor = Processor::new();
+        let flags = |terminal: &Terminal| terminal.input_mode().keyboard_flags.bits();
+
+        processor.advance(&mut terminal, b"\x1b[>1u\x1b[>11u\x1b[?u");
+        assert_eq!(flags(&terminal), 11);
+        assert_eq!(terminal.take_responses(), b"\x1b[?11u");
+
+        processor.advance(&mut terminal, b"\x1b[=4;3u");
+        assert_eq!(flags(&terminal), 11 & !4);
+        processor.advance(&mut terminal, b"\x1b[=16;2u");
+        assert_eq!(flags(&terminal), (11 & !4) | 16);
+
+        processor.advance(&mut terminal, b"\x1b[<u");
+        assert_eq!(flags(&terminal), 1);
+        processor.advance(&mut terminal, b"\x1b[<5u");
+        assert_eq!(flags(&terminal), 0);
+    }
+
+    #[test]
+    fn test_kitty_keyboard_mode_per_screen() {
+        let mut terminal = Terminal::new(5, 10, 100);
+        let mut processor = Processor::new();
+        let flags = |terminal: &Terminal| terminal.input_mode().keyboard_flags.bits();
+
+        processor.advance(&mut terminal, b"\x1b[?1049h\x1b[>1u");
+        assert_eq!(flags(&terminal), 1);
+
+        // An editor that exits without popping its flags leaves the
+        // shell's keyboard untouched
+        processor.advance(&mut terminal, b"\x1b[?1049l");
+        assert_eq!(flags(&terminal), 0);
+    }
+
+    #[test]
+    fn test_modify_other_keys() {
+        let mut terminal = Terminal::new(5, 10, 100);
+        let mut processor = Processor::new();
+        processor.advance(&mut terminal, b"\x1b[>4;2m");
+        assert_eq!(terminal.input_mode().modify_other_keys, 2);
+        processor.advance(&mut terminal, b"\x1b[>4;1m");
+        assert_eq!(terminal.input_mode().modify_other_keys, 1);
+        processor.advance(&mut terminal, b"\x1b[>4m");
+        assert_eq!(terminal.input_mode().modify_other_keys, 0);
+
+        // Setting other resources leaves the level alone
+        processor.advance(&mut terminal, b"\x1b[>4;2m\x1b[>1;2m");
+        assert_eq!(terminal.input_mode().modify_other_keys, 2);
+        processor.advance(&mut terminal, b"\x1b[>4n");
+        assert_eq!(terminal.input_mode().modify_other_keys, 0);
+    }
+
+    #[test]
+    fn test_cursor_style() {
+        let mut terminal = Terminal::new(5, 10, 100);
+        let mut processor = Processor::new();
+        assert_eq!(terminal.cursor_style(), None);
+
+        processor.advance(&mut terminal, b"\x1b[5 q");
+        assert_eq!(
+            terminal.cursor_style(),
+            Some(CursorStyle {
+                shape: CursorShape::Bar,
+                blinking: true,
+            })
+        );
+        processor.advance(&mut terminal, b"\x1b[4 q");
+        assert_eq!(
+            terminal.cursor_style(),
+            Some(CursorStyle {
+                shape: CursorShape::Underline,
+                blinking: false,
+            })
+        );
+        processor.advance(&mut terminal, b"\x1b[0 q");
+        assert_eq!(terminal.cursor_style(), None);
+    }
+
+    #[test]
+    fn test_cursor_visibility() {
+        let mut terminal = Terminal::new(5, 10, 100);
+        let mut processor = Processor::new();
+        assert!(terminal.cursor_visible());
+        processor.advance(&mut terminal, b"\x1b[?25l");
+        assert!(!terminal.cursor_visible());
+        processor.advance(&mut terminal, b"\x1b[?25h");
+        assert!(terminal.cursor_visible());
+    }
+
+    #[test]
+    fn test_mouse_modes() {
+        let mut terminal = Terminal::new(5, 10, 100);
+        let mut processor = Processor::new();
+        assert_eq!(terminal.mouse_protocol(), MouseProtocol::default());
+
+        processor.advance(&mut terminal, b"\x1b[?1002h\x1b[?1006h");
+        assert_eq!(
+            terminal.mouse_protocol(),
+            MouseProtocol {
+                mode: MouseMode::ButtonEvent,
+                encoding: MouseEncoding::Sgr,
+            }
+        );
+
+        // Turning off a format that is not in use changes nothing
+        processor.advance(&mut terminal, b"\x1b[?1015l");
+        assert_eq!(terminal.mouse_protocol().encoding, MouseEncoding::Sgr);
+
+        processor.advance(&mut terminal, b"\x1b[?1003h\x1b[?1016h");
+        assert_eq!(terminal.mouse_protocol().mode, MouseMode::AnyEvent);
+        assert_eq!(terminal.mouse_protocol().encoding, MouseEncoding::SgrPixels);
+
+        processor.advance(&mut terminal, b"\x1b[?1003l\x1b[?1016l");
+        assert_eq!(terminal.mouse_protocol(), MouseProtocol::default());
+    }
+
+    #[test]
+    fn test_bracketed_paste_mode() {
+        let mut terminal = Terminal::new(5, 10, 100);
+        let mut processor = Processor::new();
+        assert!(!terminal.bracketed_paste());
+        processor.advance(&mut terminal, b"\x1b[?2004h");
+        assert!(terminal.bracketed_paste());
+        processor.advance(&mut terminal, b"\x1b[?2004l");
+        assert!(!terminal.bracketed_paste());
     }
 }
@@ -0,0 +1,393 @@
+//! Key bindings that trigger terminal actions instead of sending input.
+//!
+//! A set of default bindings is merged with the bindings from the
+//! configuration file. A configured binding replaces a default for the
+//! same key and modifiers, and binding a key to `None` removes the
+//! default so the key reaches the shell again.
+//!
+//! While an application has modifyOtherKeys enabled, bindings for
+//! Ctrl plus a character key without Shift, such as Ctrl+0, give way so
+//! the application receives the key it asked to tell apart. Ctrl+Shift
+//! bindings stay with the terminal.
+
+use serde::Deserialize;
+use winit::keyboard::{Key, ModifiersState, NamedKey};
+
+/// Something a key binding can do.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub enum Action {
+    /// Copy the selected text to the clipboard
+    Copy,
+    /// Paste the clipboard contents
+    Paste,
+    /// Scroll the view back by one screen
+    ScrollPageUp,
+    /// Scroll the view forward by one screen
+    ScrollPageDown,
+    /// Scroll the view back by one line
+    ScrollLineUp,
+    /// Scroll the view forward by one line
+    ScrollLineDown,
+    /// Scroll the view to the oldest line in the history
+    ScrollToTop,
+    /// Return the view to the bottom of the screen
+    ScrollToBottom,
+    /// Make the font bigger
+    IncreaseFontSize,
+    /// Make the font smaller
+    DecreaseFontSize,
+    /// Return to the default font size
+    ResetFontSize,
+    /// Close the terminal
+    Quit,
+    /// Write bytes to the PTY
+    #[serde(skip)]
+    SendBytes(Vec<u8>),
+    /// Do nothing; removes a default binding
+    None,
+}
+
+/// A key that can be bound.
+#[derive(Debug, Clone, PartialEq)]
+enum BindingKey {
+    /// A named key such as `PageUp` or `F1`
+    Named(NamedKey),
+    /// A character key, stored in lowercase
+    Character(String),
+}
+
+/// A key combination bound to an action.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "RawKeyBinding")]
+pub struct KeyBinding {
+    key: BindingKey,
+    mods: ModifiersState,
+    action: Action,
+}
+
+/// A key binding as written in the configuration file.
+///
+/// ```toml
+/// [[key_bindings]]
+/// key = "PageUp"
+/// mods = "Shift"
+/// action = "ScrollPageUp"
+///
+/// [[key_bindings]]
+/// key = "F5"
+/// chars = "make\r"
+/// ```
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawKeyBinding {
+    key: String,
+    #[serde(default)]
+    mods: String,
+    action: Option<Action>,
+    chars: Option<String>,
+}
+
+impl TryFrom<RawKeyBinding> for KeyBinding {
+    type Error = String;
+
+    fn try_from(raw: RawKeyBinding) -> Result<Self, String> {
+        let key = parse_key(&raw.key).ok_or_else(|| format!("unknown key `{}`", raw.key))?;
+        let mods = parse_mods(&raw.mods)?;
+        let action = match (raw.action, raw.chars) {
+            (Some(action), None) => action,
+            (None, Some(chars)) => Action::SendBytes(chars.into_bytes()),
+            _ => {
+                return Err(format!(
+                    "binding for `{}` needs either `action` or `chars`",
+                    raw.key
+                ));
+            }
+        };
+        Ok(Self { key, mods, action })
+    }
+}
+
+impl KeyBinding {
+    /// Create a binding for a named key.
+    fn named(key: NamedKey, mods: ModifiersState, action: Action) -> Self {
+        Self {
+            key: BindingKey::Named(key),
+            mods,
+            action,
+        }
+    }
+
+    /// Create a binding for a character key.
+    fn character(c: &str, mods: ModifiersState, action: Action) -> Self {
+        Self {
+            key: BindingKey::Character(c.to_lowercase()),
+            mods,
+            action,
+        }
+    }
+
+    /// Check whether the binding is for the same key combination.
+    fn same_trigger(&self, other: &KeyBinding) -> bool {
+        self.key == other.key && self.mods == other.mods
+    }
+
+    /// Check whether the binding is for a key an application using
+    /// modifyOtherKeys expects to receive: Ctrl plus a character without
+    /// Shift.
+    fn is_application_key(&self) -> bool {
+        matches!(self.key, BindingKey::Character(_))
+            && self.mods.control_key()
+            && !self.mods.shift_key()
+    }
+
+    /// Check whether a key press triggers the binding.
+    fn matches(&self, key: &Key, mods: ModifiersState) -> bool {
+        if self.mods != mods {
+            return false;
+        }
+        match (&self.key, key) {
+            (BindingKey::Named(bound), Key::Named(named)) => bound == named,
+            (BindingKey::Character(bound), Key::Character(text)) => *bound == text.to_lowercase(),
+            _ => false,
+        }
+    }
+}
+
+/// Get the built-in key bindings.
+pub fn default_bindings() -> Vec<KeyBinding> {
+    let shift = ModifiersState::SHIFT;
+    let ctrl = ModifiersState::CONTROL;
+    let ctrl_shift = ModifiersState::CONTROL | ModifiersState::SHIFT;
+    vec![
+        KeyBinding::named(NamedKey::PageUp, shift, Action::ScrollPageUp),
+        KeyBinding::named(NamedKey::PageDown, shift, Action::ScrollPageDown),
+        KeyBinding::named(NamedKey::Home, shift, Action::ScrollToTop),
+        KeyBinding::named(NamedKey::End, shift, Action::ScrollToBottom),
+        KeyBinding::character("c", ctrl_shift, Action::Copy),
+        KeyBinding::character("v", ctrl_shift, Action::Paste),
+        KeyBinding::named(NamedKey::Insert, shift, Action::Paste),
+        KeyBinding::character("=", ctrl, Action::IncreaseFontSize),
+        KeyBinding::character("+", ctrl, Action::IncreaseFontSize),
+        KeyBinding::character("+", ctrl_shift, Action::IncreaseFontSize),
+        KeyBinding::character("-", ctrl, Action::DecreaseFontSize),
+        KeyBinding::character("0", ctrl, Action::ResetFontSize),
+        KeyBinding::character("q", ctrl_shift, Action::Quit),
+    ]
+}
+
+/// Merge configured bindings into the defaults.
+///
+/// Configured bindings replace defaults for the same key combination;
+/// bindings to `Action::None` are dropped afterwards.
+pub fn with_defaults(configured: &[KeyBinding]) -> Vec<KeyBinding> {
+    let mut bindings = default_bindings();
+    bindings.retain(|default| {
+        !configured
+            .iter()
+            .any(|binding| binding.same_trigger(default))
+    });
+    bindings.extend(configured.iter().cloned());
+    bindings.retain(|binding| binding.action != Action::None);
+    bindings
+}
+
+/// Find the action bound to a key press.
+///
+/// `modify_other_keys` is whether the application has modifyOtherKeys
+/// enabled, in which case Ctrl+character bindings without Shift are
+/// skipped.
+pub fn find_action<'a>(
+    bindings: &'a [KeyBinding],
+    key: &Key,
+    mods: ModifiersState,
+    modify_other_keys: bool,
+) -> Option<&'a Action> {
+    bindings
+        .iter()
+        .filter(|binding| !(modify_other_keys && binding.is_application_key()))
+        .find(|binding| binding.matches(key, mods))
+        .map(|binding| &binding.action)
+}
+
+/// Parse a key name from the configuration.
+///
+/// A single character names a character key; anything else must be one
+/// of the supported named keys.
+fn parse_key(name: &str) -> Option<BindingKey> {
+    let mut chars = name.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return Some(BindingKey::Character(c.to_lowercase().collect()));
+    }
+
+    let named = match name {
+        "Up" | "ArrowUp" => NamedKey::ArrowUp,
+        "Down" | "ArrowDown" => NamedKey::ArrowDown,
+        "Left" | "ArrowLeft" => NamedKey::ArrowLeft,
+        "Right" | "ArrowRight" => NamedKey::ArrowRight,
+        "Home" => NamedKey::Home,
+        "End" => NamedKey::End,
+        "PageUp" => NamedKey::PageUp,
+        "PageDown" => NamedKey::PageDown,
+        "Insert" => NamedKey::Insert,
+        "Delete" => NamedKey::Delete,
+        "Backspace" => NamedKey::Backspace,
+        "Enter" | "Return" => NamedKey::Enter,
+        "Tab" => NamedKey::Tab,
+        "Space" => NamedKey::Space,
+        "Escape" => NamedKey::Escape,
+        "F1" => NamedKey::F1,
+        "F2" => NamedKey::F2,
+        "F3" => NamedKey::F3,
+        "F4" => NamedKey::F4,
+        "F5" => NamedKey::F5,
+        "F6" => NamedKey::F6,
+        "F7" => NamedKey::F7,
+        "F8" => NamedKey::F8,
+        "F9" => NamedKey::F9,
+        "F10" => NamedKey::F10,
+        "F11" => NamedKey::F11,
+        "F12" => NamedKey::F12,
+        _ => return None,
+    };
+    Some(BindingKey::Named(named))
+}
+
+/// Parse modifiers written as `Control|Shift`. An empty string means no
+/// modifiers.
+fn parse_mods(text: &str) -> Result<ModifiersState, String> {
+    let mut mods = ModifiersState::empty();
+    for name in text
+        .split('|')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+    {
+        mods |= match name {
+            "Shift" => ModifiersState::SHIFT,
+            "Control" | "Ctrl" => ModifiersState::CONTROL,
+            "Alt" | "Option" => ModifiersState::ALT,
+            "Super" | "Command" => ModifiersState::SUPER,
+            _ => return Err(format!("unknown modifier `{}`", name)),
+        };
+    }
+    Ok(mods)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct Bindings {
+        key_bindings: Vec<KeyBinding>,
+    }
+
+    fn parse(text: &str) -> Result<Vec<KeyBinding>, toml::de::Error> {
+        toml::from_str::<Bindings>(text).map(|bindings| bindings.key_bindings)
+    }
+
+    #[test]
+    fn test_default_bindings() {
+        let bindings = with_defaults(&[]);
+        let page_up = Key::Named(NamedKey::PageUp);
+        assert_eq!(
+            find_action(&bindings, &page_up, ModifiersState::SHIFT, false),
+            Some(&Action::ScrollPageUp)
+        );
+        assert_eq!(
+            find_action(&bindings, &page_up, ModifiersState::empty(), false),
+            None
+        );
+
+        // Escape is not bound and goes to the shell
+        let escape = Key::Named(NamedKey::Escape);
+        assert_eq!(
+            find_action(&bindings, &escape, ModifiersState::empty(), false),
+            None
+        );
+
+        // Shift changes the character but not the binding
+        let paste = Key::Character("V".into());
+        let mods = ModifiersState::CONTROL | ModifiersState::SHIFT;
+        assert_eq!(
+            find_action(&bindings, &paste, mods, false),
+            Some(&Action::Paste)
+        );
+    }
+
+    #[test]
+    fn test_modify_other_keys_takes_precedence() {
+        let bindings = with_defaults(&[]);
+        let ctrl = ModifiersState::CONTROL;
+        let zero = Key::Character("0".into());
+        assert_eq!(
+            find_action(&bindings, &zero, ctrl, false),
+            Some(&Action::ResetFontSize)
+        );
+        assert_eq!(find_action(&bindings, &zero, ctrl, true), None);
+
+        // Ctrl+Shift bindings are kept
+        let copy = Key::Character("C".into());
+        let mods = ModifiersState::CONTROL | ModifiersState::SHIFT;
+        assert_eq!(
+            find_action(&bindings, &copy, mods, true),
+            Some(&Action::Copy)
+        );
+    }
+
+    #[test]
+    fn test_configured_bindings() {
+        let configured = parse(
+            r#"
+            [[key_bindings]]
+            key = "PageUp"
+            mods = "Shift"
+            action = "ScrollLineUp"
+
+            [[key_bindings]]
+            key = "F5"
+            chars = "make\r"
+            "#,
+        )
+        .unwrap();
+        let bindings = with_defaults(&configured);
+
+        let page_up = Key::Named(NamedKey::PageUp);
+        assert_eq!(
+            find_action(&bindings, &page_up, ModifiersState::SHIFT, false),
+            Some(&Action::ScrollLineUp)
+        );
+        let f5 = Key::Named(NamedKey::F5);
+        assert_eq!(
+            find_action(&bindings, &f5, ModifiersState::empty(), false),
+            Some(&Action::SendBytes(b"make\r".to_vec()))
+        );
+    }
+
+    #[test]
+    fn test_unbind_default() {
+        let configured = parse(
+            r#"
+            [[key_bindings]]
+            key = "q"
+            mods = "Control | Shift"
+            action = "None"
+            "#,
+        )
+        .unwrap();
+        let bindings = with_defaults(&configured);
+        let quit = Key::Character("Q".into());
+        let mods = ModifiersState::CONTROL | ModifiersState::SHIFT;
+        assert_eq!(find_action(&bindings, &quit, mods, false), None);
+        assert_eq!(bindings.len(), default_bindings().len() - 1);
+    }
+
+    #[test]
+    fn test_invalid_bindings() {
+        assert!(parse("[[key_bindings]]\nkey = \"Hyper\"\naction = \"Quit\"").is_err());
+        assert!(
+            parse("[[key_bindings]]\nkey = \"a\"\nmods = \"Meta\"\naction = \"Quit\"").is_err()
+        );
+        assert!(parse("[[key_bindings]]\nkey = \"a\"").is_err());
+        assert!(parse("[[key_bindings]]\nkey = \"a\"\naction = \"Launch\"").is_err());
+    }
+}
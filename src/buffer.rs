@@ -1,68 +1,117 @@
-//! Terminal output buffer for storing and rendering text.
+//! Shared terminal state fed by PTY output and read by the renderer.
 
-use crate::ansi::AnsiFilter;
+use crate::ansi::Processor;
+use crate::term::Terminal;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 
-/// Terminal buffer that stores filtered text output.
+/// Terminal model together with the parser that drives it.
+struct State {
+    processor: Processor,
+    terminal: Terminal,
+}
+
+/// Terminal buffer shared between the PTY reader thread and the UI.
 ///
-/// This buffer stores PTY output after ANSI filtering and provides
-/// access for rendering to the window.
+/// PTY output is parsed into the terminal model as it arrives, on the
+/// reader thread; the renderer reads the resulting screen contents. A
+/// dirty flag records output the UI has not handled yet, so a burst of
+/// reads only needs to notify the UI once.
 #[derive(Clone)]
 pub struct TerminalBuffer {
-    /// The filtered text content
-    content: Arc<Mutex<String>>,
-    /// ANSI filter for processing escape sequences
-    filter: Arc<Mutex<AnsiFilter>>,
+    state: Arc<Mutex<State>>,
+    dirty: Arc<AtomicBool>,
 }
 
 impl TerminalBuffer {
-    /// Create a new terminal buffer.
-    pub fn new() -> Self {
+    /// Create a new `rows` x `cols` terminal buffer, keeping up to
+    /// `scrollback` lines of history.
+    pub fn new(rows: usize, cols: usize, scrollback: usize) -> Self {
         Self {
-            content: Arc::new(Mutex::new(String::new())),
-            filter: Arc::new(Mutex::new(AnsiFilter::new())),
+            state: Arc::new(Mutex::new(State {
+                processor: Processor::new(),
+                terminal: Terminal::new(rows, cols, scrollback),
+            })),
+            dirty: Arc::new(AtomicBool::new(false)),
         }
     }
 
-    /// Process raw PTY output and add to buffer.
+    /// Process raw PTY output and apply it to the terminal.
     ///
-    /// This filters ANSI escape sequences and stores the plain text.
-    pub fn write(&self, bytes: &[u8]) {
-        let mut filter = self.filter.lock().unwrap();
-        let filtered = filter.process_slice(bytes);
-        drop(filter);
+    /// Returns `true` if the UI has to be notified: all earlier output has
+    /// been taken with `take_dirty`.
+    pub fn write(&self, bytes: &[u8]) -> bool {
+        self.advance(bytes);
+        !self.dirty.swap(true, Ordering::AcqRel)
+    }
 
-        if !filtered.is_empty() {
-            let mut content = self.content.lock().unwrap();
-            content.push_str(&filtered);
-        }
+    /// Show a message from the terminal itself, such as the banner after
+    /// the shell exits.
+    ///
+    /// Unlike `write`, this leaves the dirty flag alone: the caller
+    /// redraws, and no `take_dirty` would follow to clear the flag.
+    pub fn print(&self, message: &str) {
+        self.advance(message.as_bytes());
+    }
+
+    /// Parse `bytes` into the terminal.
+    fn advance(&self, bytes: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        let State {
+            processor,
+            terminal,
+        } = &mut *state;
+        processor.advance(terminal, bytes);
+    }
+
+    /// Check whether there is output the UI has not handled yet, and
+    /// mark it handled.
+    pub fn take_dirty(&self) -> bool {
+        self.dirty.swap(false, Ordering::AcqRel)
     }
 
-    /// Get a clone of the content for rendering.
-    pub fn content(&self) -> String {
-        let content = self.content.lock().unwrap();
-        content.clone()
+    /// Run `f` with read access to the terminal, e.g. for rendering.
+    pub fn with_terminal<R>(&self, f: impl FnOnce(&Terminal) -> R) -> R {
+        f(&self.state.lock().unwrap().terminal)
     }
 
-    /// Clear the buffer content.
-    pub fn clear(&self) {
-        let mut content = self.content.lock().unwrap();
-        content.clear();
+    /// Run `f` with write access to the terminal, e.g. to scroll the view.
+    pub fn with_terminal_mut<R>(&self, f: impl FnOnce(&mut Terminal) -> R) -> R {
+        f(&mut self.state.lock().unwrap().terminal)
     }
 
-    /// Get the content Arc for shared access.
-    pub fn content_arc(&self) -> Arc<Mutex<String>> {
-        Arc::clone(&self.content)
+    /// Get the window title requested by the application.
+    pub fn title(&self) -> String {
+        self.state.lock().unwrap().terminal.title().to_string()
     }
 
-    /// Get the filter Arc for shared access.
-    pub fn filter_arc(&self) -> Arc<Mutex<AnsiFilter>> {
-        Arc::clone(&self.filter)
+    /// Take the replies the terminal wants to send back to the PTY.
+    pub fn take_responses(&self) -> Vec<u8> {
+        self.state.lock().unwrap().terminal.take_responses()
     }
 }
 
-impl Default for TerminalBuffer {
-    fn default() -> Self {
-        Self::new()
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_output_is_coalesced() {
+        let buffer = TerminalBuffer::new(2, 10, 0);
+        assert!(buffer.write(b"a"));
+        assert!(!buffer.write(b"b"));
+        assert!(buffer.take_dirty());
+        assert!(!buffer.take_dirty());
+        assert!(buffer.write(b"c"));
+        assert_eq!(buffer.with_terminal(|term| term.grid().visible_row(0)[2].c), 'c');
+    }
+
+    #[test]
+    fn test_output_after_a_message_is_notified() {
+        // The banner shown when the shell exits must not keep the next
+        // shell's output from being reported
+        let buffer = TerminalBuffer::new(2, 20, 0);
+        buffer.print("[process exited]\r\n");
+        assert!(buffer.write(b"$ "));
     }
 }
@@ -0,0 +1,241 @@
+//! User configuration loaded from a TOML file.
+//!
+//! The file is read from `$XDG_CONFIG_HOME/my-terminal/config.toml`
+//! (falling back to `~/.config/my-terminal/config.toml`). Every setting
+//! is optional; missing settings and a missing file use the defaults.
+
+use crate::bindings::KeyBinding;
+use crate::input::AltKey;
+use crate::selection::DEFAULT_WORD_SEPARATORS;
+use crate::term::{CursorShape, CursorStyle};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// Default number of lines kept in the scrollback history.
+const DEFAULT_SCROLLBACK_LINES: usize = 10_000;
+
+/// Terminal configuration.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Maximum number of lines kept in the scrollback history
+    pub scrollback_lines: usize,
+    /// Whether Alt sends ESC before a character or composes characters
+    pub alt_key: AltKey,
+    /// Cursor shape used unless the application asks for another one
+    pub cursor_shape: CursorShape,
+    /// Whether the cursor blinks unless the application says otherwise
+    pub cursor_blinking: bool,
+    /// Ask before pasting text with line breaks, or text that pipes into
+    /// a shell, unless the application uses bracketed paste
+    pub confirm_paste: bool,
+    /// Characters that end a word when selecting with a double click
+    pub word_separators: String,
+    /// Key bindings added to or replacing the defaults
+    pub key_bindings: Vec<KeyBinding>,
+    /// The program run in the terminal
+    pub shell: ShellConfig,
+}
+
+/// How the shell is started.
+///
+/// ```toml
+/// [shell]
+/// program = "/usr/bin/fish"
+/// login = true
+/// working_directory = "~/src"
+/// on_exit = "hold"
+///
+/// [shell.env]
+/// EDITOR = "vim"
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShellConfig {
+    /// Program to run; defaults to `$SHELL`, then the shell from the
+    /// passwd entry, then `/bin/sh`
+    pub program: Option<String>,
+    /// Arguments passed to the program
+    pub args: Vec<String>,
+    /// Start the program as a login shell
+    pub login: bool,
+    /// Directory the shell starts in; defaults to the home directory
+    pub working_directory: Option<PathBuf>,
+    /// Extra environment variables for the shell
+    pub env: BTreeMap<String, String>,
+    /// What happens when the shell exits
+    pub on_exit: ExitAction,
+}
+
+/// What happens when the shell exits.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExitAction {
+    /// Close the window
+    #[default]
+    Close,
+    /// Keep the window open with a message saying how the shell exited
+    Hold,
+    /// Like `Hold`, and start the shell again when a key is pressed
+    Restart,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            scrollback_lines: DEFAULT_SCROLLBACK_LINES,
+            alt_key: AltKey::default(),
+            cursor_shape: CursorShape::default(),
+            cursor_blinking: false,
+            confirm_paste: true,
+            word_separators: DEFAULT_WORD_SEPARATORS.to_string(),
+            key_bindings: Vec::new(),
+            shell: ShellConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Load the configuration from the default location.
+    ///
+    /// A missing file is not an error. If the file cannot be read or
+    /// parsed the problem is logged and the defaults are used, so a typo
+    /// in the configuration never prevents the terminal from starting.
+    pub fn load() -> Self {
+        let Some(path) = Self::path() else {
+            return Self::default();
+        };
+
+        match std::fs::read_to_string(&path) {
+            Ok(text) => match Self::parse(&text) {
+                Ok(config) => {
+                    log::info!("Loaded configuration from {}", path.display());
+                    config
+                }
+                Err(e) => {
+                    log::error!("Invalid configuration in {}: {}", path.display(), e);
+                    Self::default()
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Self::default(),
+            Err(e) => {
+                log::error!("Failed to read {}: {}", path.display(), e);
+                Self::default()
+            }
+        }
+    }
+
+    /// Get the cursor style to use when the application has not set one.
+    pub fn cursor_style(&self) -> CursorStyle {
+        CursorStyle {
+            shape: self.cursor_shape,
+            blinking: self.cursor_blinking,
+        }
+    }
+
+    /// Parse a configuration from TOML text.
+    pub fn parse(text: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(text)
+    }
+
+    /// Get the path of the configuration file.
+    pub fn path() -> Option<PathBuf> {
+        let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+        Some(config_dir.join("my-terminal").join("config.toml"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_empty_config_uses_defaults() {
+        let config = Config::parse("").unwrap();
+        assert_eq!(config.scrollback_lines, DEFAULT_SCROLLBACK_LINES);
+    }
+
+    #[test]
+    fn test_scrollback_lines() {
+        let config = Config::parse("scrollback_lines = 500").unwrap();
+        assert_eq!(config.scrollback_lines, 500);
+    }
+
+    #[test]
+    fn test_alt_key() {
+        assert_eq!(Config::parse("").unwrap().alt_key, AltKey::Escape);
+        let config = Config::parse("alt_key = \"compose\"").unwrap();
+        assert_eq!(config.alt_key, AltKey::Compose);
+        assert!(Config::parse("alt_key = \"meta\"").is_err());
+    }
+
+    #[test]
+    fn test_cursor_style() {
+        assert_eq!(
+            Config::parse("").unwrap().cursor_style(),
+            CursorStyle::default()
+        );
+        let config = Config::parse("cursor_shape = \"bar\"\ncursor_blinking = true").unwrap();
+        assert_eq!(
+            config.cursor_style(),
+            CursorStyle {
+                shape: CursorShape::Bar,
+                blinking: true,
+            }
+        );
+    }
+
+    #[test]
+    fn test_confirm_paste() {
+        assert!(Config::parse("").unwrap().confirm_paste);
+        assert!(
+            !Config::parse("confirm_paste = false")
+                .unwrap()
+                .confirm_paste
+        );
+    }
+
+    #[test]
+    fn test_word_separators() {
+        let config = Config::parse("").unwrap();
+        assert_eq!(config.word_separators, DEFAULT_WORD_SEPARATORS);
+        let config = Config::parse("word_separators = \" /\"").unwrap();
+        assert_eq!(config.word_separators, " /");
+    }
+
+    #[test]
+    fn test_shell() {
+        assert_eq!(Config::parse("").unwrap().shell, ShellConfig::default());
+        let config = Config::parse(
+            r#"
+            [shell]
+            program = "fish"
+            args = ["--private"]
+            login = true
+            working_directory = "/tmp"
+
+            [shell.env]
+            EDITOR = "vim"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.shell.program.as_deref(), Some("fish"));
+        assert_eq!(config.shell.args, ["--private"]);
+        assert!(config.shell.login);
+        assert_eq!(config.shell.working_directory, Some(PathBuf::from("/tmp")));
+        assert_eq!(config.shell.env["EDITOR"], "vim");
+        assert_eq!(config.shell.on_exit, ExitAction::Close);
+
+        let config = Config::parse("[shell]\non_exit = \"restart\"").unwrap();
+        assert_eq!(config.shell.on_exit, ExitAction::Restart);
+    }
+
+    #[test]
+    fn test_unknown_field_is_rejected() {
+        assert!(Config::parse("scrollbak_lines = 500").is_err());
+    }
+}
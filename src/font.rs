@@ -4,7 +4,23 @@
 //! simple, dependency-free font rasterization.
 
 use fontdue::{Font, FontSettings};
-use std::sync::Arc;
+
+/// Horizontal shift per pixel above the baseline used to slant italic
+/// text, about 11 degrees.
+const ITALIC_SLANT: f32 = 0.2;
+
+/// How to style a glyph drawn with the regular font face.
+///
+/// There is only one embedded face, so bold is synthesized by
+/// overstriking the glyph one pixel to the right and italic by
+/// shearing it.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct GlyphStyle {
+    /// Thicken the strokes
+    pub bold: bool,
+    /// Slant the glyph to the right
+    pub italic: bool,
+}
 
 /// Terminal font renderer.
 ///
@@ -18,6 +34,8 @@ pub struct FontRenderer {
     char_width: usize,
     /// Character height in pixels
     char_height: usize,
+    /// Distance from the top of a cell to the baseline in pixels
+    ascent: usize,
 }
 
 impl FontRenderer {
@@ -55,16 +73,28 @@ impl FontRenderer {
 
         // Calculate character dimensions
         let char_width = (font_size * 0.6) as usize; // Approximate monospace width
-        let char_height = font_size.ceil() as usize;
+        let (char_height, ascent) = match font.horizontal_line_metrics(font_size) {
+            Some(metrics) => (
+                metrics.new_line_size.ceil() as usize,
+                metrics.ascent.ceil() as usize,
+            ),
+            None => (font_size.ceil() as usize, font_size.ceil() as usize),
+        };
 
         Self {
             font,
             font_size,
             char_width,
             char_height,
+            ascent,
         }
     }
 
+    /// Get the font size in pixels.
+    pub fn font_size(&self) -> f32 {
+        self.font_size
+    }
+
     /// Get the character width in pixels.
     pub fn char_width(&self) -> usize {
         self.char_width
@@ -75,21 +105,26 @@ impl FontRenderer {
         self.char_height
     }
 
-    /// Get the font size in pixels.
-    pub fn font_size(&self) -> f32 {
-        self.font_size
+    /// Get the distance from the top of a cell to the baseline in pixels.
+    pub fn baseline(&self) -> usize {
+        self.ascent
     }
 
     /// Render a single character to a pixel buffer.
     ///
     /// # Arguments
     /// * `c` - The character to render
-    /// * `x` - X position in the buffer
-    /// * `y` - Y position in the buffer
+    /// * `x` - X position of the character cell in the buffer
+    /// * `y` - Y position of the character cell in the buffer
     /// * `buffer` - The pixel buffer to draw to
     /// * `buffer_width` - Width of the buffer in pixels
     /// * `buffer_height` - Height of the buffer in pixels
     /// * `color` - ARGB color value (0xAARRGGBB)
+    /// * `style` - Synthesized bold and italic
+    ///
+    /// The glyph is placed on the cell's baseline, so `(x, y)` is the
+    /// top-left corner of the cell rather than of the glyph bitmap.
+    #[allow(clippy::too_many_arguments)]
     pub fn render_char(
         &self,
         c: char,
@@ -99,25 +134,52 @@ impl FontRenderer {
         buffer_width: usize,
         buffer_height: usize,
         color: u32,
+        style: GlyphStyle,
     ) {
         // Rasterize the character
         let (metrics, bitmap) = self.font.rasterize(c, self.font_size);
 
+        // Position the glyph bitmap relative to the baseline
+        let origin_x = x as i32 + metrics.xmin;
+        let origin_y = (y + self.ascent) as i32 - metrics.height as i32 - metrics.ymin;
+
         // Extract color components
-        let a = ((color >> 24) & 0xFF) as u32;
-        let r = ((color >> 16) & 0xFF) as u32;
-        let g = ((color >> 8) & 0xFF) as u32;
-        let b = (color & 0xFF) as u32;
+        let a = (color >> 24) & 0xFF;
+        let r = (color >> 16) & 0xFF;
+        let g = (color >> 8) & 0xFF;
+        let b = color & 0xFF;
+
+        // Bold glyphs are one pixel wider: each pixel also covers the
+        // one to its right
+        let width = metrics.width + usize::from(style.bold);
+        let baseline_y = (y + self.ascent) as i32;
 
         // Draw each pixel of the glyph
-        for (i, &alpha) in bitmap.iter().enumerate() {
+        for i in 0..width * metrics.height {
+            let (col, row) = (i % width, i / width);
+            let coverage = |col: usize| match col < metrics.width {
+                true => bitmap[row * metrics.width + col],
+                false => 0,
+            };
+            let mut alpha = coverage(col);
+            if style.bold && col > 0 {
+                alpha = alpha.max(coverage(col - 1));
+            }
             if alpha == 0 {
                 continue;
             }
 
-            let glyph_x = x + (i % metrics.width);
-            let glyph_y = y + (i / metrics.width);
+            let glyph_y = origin_y + row as i32;
+            let slant = match style.italic {
+                true => ((baseline_y - glyph_y) as f32 * ITALIC_SLANT).round() as i32,
+                false => 0,
+            };
+            let glyph_x = origin_x + col as i32 + slant;
 
+            if glyph_x < 0 || glyph_y < 0 {
+                continue;
+            }
+            let (glyph_x, glyph_y) = (glyph_x as usize, glyph_y as usize);
             if glyph_x >= buffer_width || glyph_y >= buffer_height {
                 continue;
             }
@@ -143,93 +205,6 @@ impl FontRenderer {
             buffer[idx] = (new_a << 24) | (new_r << 16) | (new_g << 8) | new_b;
         }
     }
-
-    /// Render a string to a pixel buffer.
-    ///
-    /// # Arguments
-    /// * `text` - The text to render
-    /// * `x` - X position in the buffer
-    /// * `y` - Y position in the buffer
-    /// * `buffer` - The pixel buffer to draw to
-    /// * `buffer_width` - Width of the buffer in pixels
-    /// * `buffer_height` - Height of the buffer in pixels
-    /// * `color` - ARGB color value (0xAARRGGBB)
-    pub fn render_text(
-        &self,
-        text: &str,
-        mut x: usize,
-        y: usize,
-        buffer: &mut [u32],
-        buffer_width: usize,
-        buffer_height: usize,
-        color: u32,
-    ) {
-        for c in text.chars() {
-            match c {
-                '\n' => {
-                    // Line break: move to next line
-                    x = 0;
-                    // Note: y increment should be handled by caller for multi-line rendering
-                }
-                '\r' => {
-                    // Carriage return: move to start of line
-                    x = 0;
-                }
-                '\t' => {
-                    // Tab: move to next tab stop (every 8 characters)
-                    x = ((x / self.char_width / 8) + 1) * 8 * self.char_width;
-                }
-                _ if c.is_control() => {
-                    // Other control characters: skip
-                }
-                _ => {
-                    // Regular character: render it
-                    self.render_char(c, x, y, buffer, buffer_width, buffer_height, color);
-                    x += self.char_width;
-                }
-            }
-
-            // Stop if we've gone past the buffer width
-            if x >= buffer_width {
-                break;
-            }
-        }
-    }
-
-    /// Render multiple lines of text to a pixel buffer.
-    ///
-    /// # Arguments
-    /// * `lines` - Iterator over lines of text
-    /// * `x` - X position in the buffer
-    /// * `y` - Y position in the buffer (first line)
-    /// * `buffer` - The pixel buffer to draw to
-    /// * `buffer_width` - Width of the buffer in pixels
-    /// * `buffer_height` - Height of the buffer in pixels
-    /// * `color` - ARGB color value (0xAARRGGBB)
-    /// * `visible_lines` - Maximum number of lines to render
-    pub fn render_lines(
-        &self,
-        lines: impl Iterator<Item = String>,
-        mut x: usize,
-        mut y: usize,
-        buffer: &mut [u32],
-        buffer_width: usize,
-        buffer_height: usize,
-        color: u32,
-        visible_lines: usize,
-    ) {
-        for (line_num, line) in lines.enumerate() {
-            if line_num >= visible_lines {
-                break;
-            }
-            if y + self.char_height > buffer_height {
-                break;
-            }
-
-            self.render_text(&line, x, y, buffer, buffer_width, buffer_height, color);
-            y += self.char_height;
-        }
-    }
 }
 
 impl Default for FontRenderer {
@@ -237,71 +212,3 @@ impl Default for FontRenderer {
         Self::new()
     }
 }
-
-/// Split text into lines suitable for terminal display.
-///
-/// Handles both \n and \r\n line endings.
-pub fn split_lines(text: &str) -> Vec<String> {
-    let mut lines = Vec::new();
-    let mut current_line = String::new();
-    let mut chars = text.chars().peekable();
-
-    while let Some(c) = chars.next() {
-        match c {
-            '\r' => {
-                // Check for CRLF
-                if chars.peek() == Some(&'\n') {
-                    chars.next(); // Skip the \n
-                }
-                // Carriage return: end current line
-                if !current_line.is_empty() {
-                    lines.push(current_line.clone());
-                }
-                current_line.clear();
-            }
-            '\n' => {
-                // Line feed: end current line
-                if !current_line.is_empty() {
-                    lines.push(current_line.clone());
-                }
-                lines.push(String::new()); // Empty line for explicit line breaks
-                current_line.clear();
-            }
-            _ => {
-                current_line.push(c);
-            }
-        }
-    }
-
-    // Add the last line if it has content
-    if !current_line.is_empty() {
-        lines.push(current_line);
-    }
-
-    lines
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn test_split_lines_basic() {
-        let text = "line1\nline2\nline3";
-        let lines = split_lines(text);
-        assert_eq!(lines.len(), 3);
-        assert_eq!(lines[0], "line1");
-        assert_eq!(lines[1], "line2");
-        assert_eq!(lines[2], "line3");
-    }
-
-    #[test]
-    fn test_split_lines_crlf() {
-        let text = "line1\r\nline2\r\nline3";
-        let lines = split_lines(text);
-        assert_eq!(lines.len(), 3);
-        assert_eq!(lines[0], "line1");
-        assert_eq!(lines[1], "line2");
-        assert_eq!(lines[2], "line3");
-    }
-}
@@ -1,53 +1,865 @@
-//! Terminal grid data structure - simplified version.
+//! Terminal grid data structure.
 //!
-//! This module defines a simple grid to store terminal output
-//! without complex ANSI parsing for now.
+//! This module defines the cell-based screen storage used by the
+//! terminal model. Each cell holds a character together with its
+//! colors and attribute flags; the escape sequence handling that
+//! decides what goes where lives in `term.rs`.
 
+use std::collections::VecDeque;
 use std::fmt;
+use std::ops::{Deref, DerefMut, RangeInclusive};
 
-/// Terminal grid storing plain text output.
+/// A terminal color.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Color {
+    /// The default foreground or background color
+    #[default]
+    Default,
+    /// An entry of the 256-color palette (0-15 are the ANSI colors)
+    Indexed(u8),
+    /// A 24-bit color
+    Rgb(u8, u8, u8),
+}
+
+/// Cell attribute flags.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Flags(u16);
+
+impl Flags {
+    /// Bold text (SGR 1)
+    pub const BOLD: Self = Self(1 << 0);
+    /// Dim text (SGR 2)
+    pub const DIM: Self = Self(1 << 1);
+    /// Italic text (SGR 3)
+    pub const ITALIC: Self = Self(1 << 2);
+    /// Underlined text (SGR 4)
+    pub const UNDERLINE: Self = Self(1 << 3);
+    /// Swapped foreground and background (SGR 7)
+    pub const INVERSE: Self = Self(1 << 4);
+    /// Struck-through text (SGR 9)
+    pub const STRIKETHROUGH: Self = Self(1 << 5);
+    /// Invisible text (SGR 8)
+    pub const HIDDEN: Self = Self(1 << 6);
+    /// The cell holds a double-width character
+    pub const WIDE_CHAR: Self = Self(1 << 7);
+    /// The cell is the right half of a double-width character
+    pub const WIDE_CHAR_SPACER: Self = Self(1 << 8);
+
+    /// Check whether all flags in `other` are set.
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Set the flags in `other`.
+    pub fn insert(&mut self, other: Self) {
+        self.0 |= other.0;
+    }
+
+    /// Clear the flags in `other`.
+    pub fn remove(&mut self, other: Self) {
+        self.0 &= !other.0;
+    }
+}
+
+impl std::ops::BitOr for Flags {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+/// A single character cell.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cell {
+    /// The base character
+    pub c: char,
+    /// Combining marks drawn on top of the base character
+    pub zerowidth: Vec<char>,
+    /// Foreground color
+    pub fg: Color,
+    /// Background color
+    pub bg: Color,
+    /// Attribute flags
+    pub flags: Flags,
+}
+
+impl Default for Cell {
+    fn default() -> Self {
+        Self {
+            c: ' ',
+            zerowidth: Vec::new(),
+            fg: Color::Default,
+            bg: Color::Default,
+            flags: Flags::default(),
+        }
+    }
+}
+
+impl Cell {
+    /// Create an empty cell that keeps the background color of
+    /// `template`, as erase operations do.
+    pub fn blank(template: &Cell) -> Self {
+        Self {
+            bg: template.bg,
+            ..Self::default()
+        }
+    }
+}
+
+/// A row of cells.
+#[derive(Debug, Clone, PartialEq)]
+struct Row {
+    /// The cells of the row
+    cells: Vec<Cell>,
+    /// The text continues on the next row because it wrapped at the
+    /// right edge rather than ending with a newline
+    wrapped: bool,
+}
+
+impl Row {
+    /// Create a row of `cols` empty cells.
+    fn new(cols: usize) -> Self {
+        Self {
+            cells: vec![Cell::default(); cols],
+            wrapped: false,
+        }
+    }
+
+    /// Check whether the row holds nothing but empty cells.
+    fn is_blank(&self) -> bool {
+        !self.wrapped && self.cells.iter().all(|cell| *cell == Cell::default())
+    }
+}
+
+impl Deref for Row {
+    type Target = [Cell];
+
+    fn deref(&self) -> &[Cell] {
+        &self.cells
+    }
+}
+
+impl DerefMut for Row {
+    fn deref_mut(&mut self) -> &mut [Cell] {
+        &mut self.cells
+    }
+}
+
+/// Cursor position on the grid (zero-based).
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Cursor {
+    /// Row index
+    pub row: usize,
+    /// Column index
+    pub col: usize,
+}
+
+/// Which part of the screen or line an erase operation covers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClearMode {
+    /// From the cursor to the end (inclusive)
+    Below,
+    /// From the start to the cursor (inclusive)
+    Above,
+    /// Everything
+    All,
+}
+
+/// Terminal grid of cells with a cursor and scrollback history.
+///
+/// Lines scrolled off the top of the screen are kept in a bounded ring
+/// buffer. The view can be scrolled back into that history without
+/// affecting the screen contents programs write to.
 pub struct Grid {
     /// Number of rows in the grid.
     pub rows: usize,
     /// Number of columns in the grid.
     pub cols: usize,
-    /// The grid cells as plain text.
-    pub cells: Vec<Vec<String>>,
+    /// Current cursor position.
+    pub cursor: Cursor,
+    /// The grid cells, one row per line of the screen.
+    lines: Vec<Row>,
+    /// Lines scrolled off the top of the screen, oldest first.
+    history: VecDeque<Row>,
+    /// Maximum number of lines kept in `history`.
+    max_history: usize,
+    /// Number of lines the view is scrolled back into the history.
+    display_offset: usize,
+    /// Number of lines that have moved into the history so far. Line
+    /// numbers count from the first of them, so a line keeps its number
+    /// while it scrolls.
+    scrolled_lines: usize,
 }
 
 impl Grid {
-    /// Create a new grid with the specified dimensions.
-    pub fn new(rows: usize, cols: usize) -> Self {
+    /// Create a new grid with the specified dimensions, keeping up to
+    /// `max_history` lines of scrollback.
+    pub fn new(rows: usize, cols: usize, max_history: usize) -> Self {
         log::debug!("Creating {}x{} grid", rows, cols);
 
-        let cells = vec![vec![String::new(); cols]; rows];
+        let lines = vec![Row::new(cols); rows];
+
+        Self {
+            rows,
+            cols,
+            cursor: Cursor::default(),
+            lines,
+            history: VecDeque::new(),
+            max_history,
+            display_offset: 0,
+            scrolled_lines: 0,
+        }
+    }
+
+    /// Get the cells of a row of the view, taking the display offset into
+    /// account. Row 0 is the top of the window.
+    pub fn visible_row(&self, row: usize) -> &[Cell] {
+        if row < self.display_offset {
+            &self.history[self.history.len() - self.display_offset + row]
+        } else {
+            &self.lines[row - self.display_offset]
+        }
+    }
+
+    /// Get the line number of a row of the view.
+    pub fn visible_line(&self, row: usize) -> usize {
+        self.scrolled_lines - self.display_offset + row
+    }
+
+    /// Get the row holding a line, if it is still in the history or on
+    /// the screen.
+    fn line_row(&self, line: usize) -> Option<&Row> {
+        let index = line.checked_sub(self.scrolled_lines - self.history.len())?;
+        match index.checked_sub(self.history.len()) {
+            None => self.history.get(index),
+            Some(row) => self.lines.get(row),
+        }
+    }
+
+    /// Get the cells of a line, if it is still in the history or on the
+    /// screen.
+    pub fn line(&self, line: usize) -> Option<&[Cell]> {
+        self.line_row(line).map(|row| &row[..])
+    }
+
+    /// Check whether a line continues on the next line because of a soft
+    /// wrap.
+    pub fn is_wrapped(&self, line: usize) -> bool {
+        self.line_row(line).is_some_and(|row| row.wrapped)
+    }
+
+    /// Get the maximum number of lines kept in the scrollback history.
+    pub fn max_history(&self) -> usize {
+        self.max_history
+    }
+
+    /// Get the number of lines the view is scrolled back.
+    pub fn display_offset(&self) -> usize {
+        self.display_offset
+    }
+
+    /// Scroll the view by `lines`; positive values scroll back into the
+    /// history. The offset is clamped to the available history.
+    pub fn scroll_display(&mut self, lines: isize) {
+        let offset = (self.display_offset as isize).saturating_add(lines);
+        self.display_offset = offset.clamp(0, self.history.len() as isize) as usize;
+    }
+
+    /// Return the view to the bottom of the screen.
+    pub fn scroll_to_bottom(&mut self) {
+        self.display_offset = 0;
+    }
+
+    /// Resize the grid to `rows` x `cols`, reflowing the text.
+    ///
+    /// Rows joined by a soft wrap form one logical line, which is wrapped
+    /// again at the new width, both on the screen and in the history.
+    /// Blank rows below the cursor are dropped first; after that, lines
+    /// that no longer fit move into the history, and when the grid gets
+    /// taller lines are pulled back from it. The cursor stays on the
+    /// character it was on, or on the top row if that line moved into
+    /// the history.
+    pub fn resize(&mut self, rows: usize, cols: usize) {
+        if rows == 0 || cols == 0 || (rows == self.rows && cols == self.cols) {
+            return;
+        }
+        log::debug!(
+            "Resizing grid from {}x{} to {}x{}",
+            self.rows,
+            self.cols,
+            rows,
+            cols
+        );
+
+        let cursor_row = self.cursor.row.min(self.rows - 1);
+        let blank_below = self.lines[cursor_row + 1..]
+            .iter()
+            .rev()
+            .take_while(|row| row.is_blank())
+            .count();
+        self.lines.truncate(self.rows - blank_below);
+
+        let cursor_line = self.history.len() + cursor_row;
+        let old: Vec<Row> = self.history.drain(..).chain(self.lines.drain(..)).collect();
+
+        let mut reflowed = Vec::new();
+        let mut cursor = Cursor::default();
+        let mut logical: Vec<Cell> = Vec::new();
+        let mut cursor_offset = None;
+        let mut ends_in_padding = false;
+        for (index, row) in old.into_iter().enumerate() {
+            let mut cells = row.cells;
+
+            // A blank cell left at the end of a wrapped row because the
+            // double-width character after it did not fit
+            if ends_in_padding
+                && cells
+                    .first()
+                    .is_some_and(|c| c.flags.contains(Flags::WIDE_CHAR))
+            {
+                logical.pop();
+            }
+            ends_in_padding = row.wrapped && cells.last() == Some(&Cell::default());
+
+            if index == cursor_line {
+                cursor_offset = Some(logical.len() + self.cursor.col.min(cells.len() - 1));
+            }
+            if !row.wrapped {
+                while cells.last() == Some(&Cell::default()) {
+                    cells.pop();
+                }
+            }
+            logical.append(&mut cells);
+
+            if !row.wrapped {
+                if let Some(offset) = cursor_offset
+                    && logical.len() <= offset
+                {
+                    logical.resize(offset + 1, Cell::default());
+                }
+                rewrap(
+                    &mut reflowed,
+                    std::mem::take(&mut logical),
+                    cols,
+                    cursor_offset.take(),
+                    &mut cursor,
+                );
+            }
+        }
+        if !logical.is_empty() {
+            rewrap(&mut reflowed, logical, cols, cursor_offset, &mut cursor);
+        }
+
+        let start = reflowed.len().saturating_sub(rows);
+        self.lines = reflowed.split_off(start);
+        self.lines.resize(rows, Row::new(cols));
+
+        let excess = reflowed.len().saturating_sub(self.max_history);
+        self.history = reflowed.into_iter().skip(excess).collect();
+        self.scrolled_lines = self.scrolled_lines.max(self.history.len());
+
+        self.rows = rows;
+        self.cols = cols;
+        self.cursor = Cursor {
+            row: cursor.row.saturating_sub(start),
+            col: cursor.col,
+        };
+        self.display_offset = self.display_offset.min(self.history.len());
+    }
+
+    /// Discard the scrollback history.
+    pub fn clear_history(&mut self) {
+        self.history.clear();
+        self.display_offset = 0;
+    }
+
+    /// Mark a row as continuing on the next row because the text in it
+    /// wrapped at the right edge.
+    pub fn set_wrapped(&mut self, row: usize) {
+        if let Some(row) = self.lines.get_mut(row) {
+            row.wrapped = true;
+        }
+    }
+
+    /// Write a character at the cursor without moving it.
+    ///
+    /// `template` provides the colors and flags. A character of width 2
+    /// also claims the next cell as its spacer. Any double-width character
+    /// partially overwritten is cleared.
+    pub fn put_char(&mut self, c: char, width: usize, template: &Cell) {
+        let Cursor { row, col } = self.cursor;
+        if row >= self.rows || col >= self.cols {
+            return;
+        }
+        let wide = width == 2 && col + 1 < self.cols;
+
+        self.clear_wide_fragments(row, col);
+        if wide {
+            self.clear_wide_fragments(row, col + 1);
+        }
+
+        let cell = &mut self.lines[row][col];
+        cell.c = c;
+        cell.zerowidth.clear();
+        cell.fg = template.fg;
+        cell.bg = template.bg;
+        cell.flags = template.flags;
+
+        if wide {
+            cell.flags.insert(Flags::WIDE_CHAR);
+            let spacer = &mut self.lines[row][col + 1];
+            *spacer = Cell::blank(template);
+            spacer.flags.insert(Flags::WIDE_CHAR_SPACER);
+        }
+    }
+
+    /// Attach a combining mark to the cell at a position.
+    ///
+    /// If the position is the spacer of a double-width character, the
+    /// mark goes to the character itself.
+    pub fn push_zerowidth(&mut self, row: usize, col: usize, c: char) {
+        if row >= self.rows || col >= self.cols {
+            return;
+        }
+        let col = if col > 0 && self.lines[row][col].flags.contains(Flags::WIDE_CHAR_SPACER) {
+            col - 1
+        } else {
+            col
+        };
+        self.lines[row][col].zerowidth.push(c);
+    }
 
-        Self { rows, cols, cells }
+    /// Erase part or all of the screen relative to the cursor (ED).
+    pub fn erase_in_display(&mut self, mode: ClearMode, template: &Cell) {
+        let Cursor { row, col } = self.cursor;
+        match mode {
+            ClearMode::Below => {
+                self.clear_range(row, col, self.cols, template);
+                for r in row + 1..self.rows {
+                    self.clear_row(r, template);
+                }
+            }
+            ClearMode::Above => {
+                for r in 0..row {
+                    self.clear_row(r, template);
+                }
+                self.clear_range(row, 0, col + 1, template);
+            }
+            ClearMode::All => {
+                for r in 0..self.rows {
+                    self.clear_row(r, template);
+                }
+            }
+        }
     }
 
-    /// Write a character at the specified position.
-    pub fn write_char(&mut self, row: usize, col: usize, c: char) {
-        if row < self.rows && col < self.cols {
-            self.cells[row][col].push(c);
+    /// Erase part or all of the cursor line (EL).
+    pub fn erase_in_line(&mut self, mode: ClearMode, template: &Cell) {
+        let Cursor { row, col } = self.cursor;
+        match mode {
+            ClearMode::Below => self.clear_range(row, col, self.cols, template),
+            ClearMode::Above => self.clear_range(row, 0, col + 1, template),
+            ClearMode::All => self.clear_row(row, template),
         }
     }
 
-    /// Write a string at the specified position.
-    pub fn write_str(&mut self, row: usize, col: usize, s: &str) {
-        if row < self.rows && col < self.cols {
-            self.cells[row][col].push_str(s);
+    /// Erase `count` cells from the cursor without shifting the rest of
+    /// the line (ECH).
+    pub fn erase_chars(&mut self, count: usize, template: &Cell) {
+        let Cursor { row, col } = self.cursor;
+        self.clear_range(row, col, col.saturating_add(count), template);
+    }
+
+    /// Insert `count` blank cells at the cursor, shifting the rest of the
+    /// row to the right (ICH). Cells pushed past the right edge are lost.
+    pub fn insert_blank(&mut self, count: usize, template: &Cell) {
+        let Cursor { row, col } = self.cursor;
+        if row >= self.rows || col >= self.cols {
+            return;
+        }
+        let count = count.min(self.cols - col);
+        self.clear_wide_fragments(row, col);
+        self.lines[row][col..].rotate_right(count);
+        self.clear_range(row, col, col + count, template);
+
+        // A double-width character whose spacer was pushed off the edge
+        let last = &mut self.lines[row][self.cols - 1];
+        if last.flags.contains(Flags::WIDE_CHAR) {
+            *last = Cell::blank(template);
+        }
+    }
+
+    /// Delete `count` cells at the cursor, shifting the rest of the row to
+    /// the left and filling the right edge with blanks (DCH).
+    pub fn delete_chars(&mut self, count: usize, template: &Cell) {
+        let Cursor { row, col } = self.cursor;
+        if row >= self.rows || col >= self.cols {
+            return;
+        }
+        let count = count.min(self.cols - col);
+        self.clear_wide_fragments(row, col);
+        self.clear_wide_fragments(row, col + count - 1);
+        self.lines[row][col..].rotate_left(count);
+        self.clear_range(row, self.cols - count, self.cols, template);
+    }
+
+    /// Insert `count` blank lines at the cursor row, pushing the lines
+    /// below it down within `region` (IL).
+    pub fn insert_lines(&mut self, count: usize, region: RangeInclusive<usize>, template: &Cell) {
+        if region.contains(&self.cursor.row) {
+            self.scroll_down(self.cursor.row..=*region.end(), count, template);
+        }
+    }
+
+    /// Delete `count` lines at the cursor row, pulling the lines below it
+    /// up within `region` (DL).
+    pub fn delete_lines(&mut self, count: usize, region: RangeInclusive<usize>, template: &Cell) {
+        if region.contains(&self.cursor.row) {
+            self.shift_up(self.cursor.row..=*region.end(), count, template);
+        }
+    }
+
+    /// Scroll the rows in `region` up by `count` lines.
+    ///
+    /// Blank lines are inserted at the bottom. Lines scrolled off the top
+    /// of the screen (a region starting at row 0) move into the history;
+    /// otherwise they are discarded. A view scrolled back into the history
+    /// stays on the same content.
+    pub fn scroll_up(&mut self, region: RangeInclusive<usize>, count: usize, template: &Cell) {
+        let (top, bottom) = region.clone().into_inner();
+        if top == 0 && bottom < self.rows && self.max_history > 0 {
+            let count = count.min(bottom + 1);
+            for line in &self.lines[..count] {
+                if self.history.len() == self.max_history {
+                    self.history.pop_front();
+                }
+                self.history.push_back(line.clone());
+            }
+            self.scrolled_lines += count;
+            if self.display_offset > 0 {
+                self.display_offset = (self.display_offset + count).min(self.history.len());
+            }
+        }
+        self.shift_up(region, count, template);
+    }
+
+    /// Move the rows in `region` up by `count` lines, discarding the lines
+    /// moved out of the region and inserting blank lines at the bottom.
+    fn shift_up(&mut self, region: RangeInclusive<usize>, count: usize, template: &Cell) {
+        let (top, bottom) = region.into_inner();
+        if top > bottom || bottom >= self.rows {
+            return;
+        }
+        let count = count.min(bottom - top + 1);
+        self.lines[top..=bottom].rotate_left(count);
+        for row in bottom + 1 - count..=bottom {
+            self.clear_row(row, template);
+        }
+    }
+
+    /// Scroll the rows in `region` down by `count` lines.
+    ///
+    /// Lines scrolled off the bottom of the region are discarded and blank
+    /// lines are inserted at the top.
+    pub fn scroll_down(&mut self, region: RangeInclusive<usize>, count: usize, template: &Cell) {
+        let (top, bottom) = region.into_inner();
+        if top > bottom || bottom >= self.rows {
+            return;
+        }
+        let count = count.min(bottom - top + 1);
+        self.lines[top..=bottom].rotate_right(count);
+        for row in top..top + count {
+            self.clear_row(row, template);
+        }
+    }
+
+    /// Clear the cells in `start..end` of a row. Clearing up to the end
+    /// of the row also ends a soft wrap.
+    fn clear_range(&mut self, row: usize, start: usize, end: usize, template: &Cell) {
+        if row >= self.rows || start >= self.cols {
+            return;
+        }
+        let end = end.min(self.cols);
+        if end == self.cols {
+            self.lines[row].wrapped = false;
+        }
+        self.clear_wide_fragments(row, start);
+        self.clear_wide_fragments(row, end - 1);
+        for cell in &mut self.lines[row][start..end] {
+            *cell = Cell::blank(template);
+        }
+    }
+
+    /// Clear an entire row.
+    fn clear_row(&mut self, row: usize, template: &Cell) {
+        self.clear_range(row, 0, self.cols, template);
+    }
+
+    /// If the cell at a position is one half of a double-width character,
+    /// clear the other half so no orphaned half remains after the cell is
+    /// overwritten.
+    fn clear_wide_fragments(&mut self, row: usize, col: usize) {
+        let flags = self.lines[row][col].flags;
+        if flags.contains(Flags::WIDE_CHAR) && col + 1 < self.cols {
+            let spacer = &mut self.lines[row][col + 1];
+            spacer.flags.remove(Flags::WIDE_CHAR_SPACER);
+            spacer.c = ' ';
+        } else if flags.contains(Flags::WIDE_CHAR_SPACER) && col > 0 {
+            let wide = &mut self.lines[row][col - 1];
+            wide.flags.remove(Flags::WIDE_CHAR);
+            wide.c = ' ';
+            wide.zerowidth.clear();
         }
     }
 }
 
+/// Wrap a logical line into rows of `cols` cells and append them to
+/// `rows`.
+///
+/// A double-width character that would be cut by the right edge moves to
+/// the next row. If `cursor_offset` is the index of a cell in `line`, the
+/// position the cell ends up at is stored in `cursor`.
+fn rewrap(
+    rows: &mut Vec<Row>,
+    line: Vec<Cell>,
+    cols: usize,
+    cursor_offset: Option<usize>,
+    cursor: &mut Cursor,
+) {
+    let mut row = Row::new(cols);
+    let mut col = 0;
+    for (index, mut cell) in line.into_iter().enumerate() {
+        let wide = cell.flags.contains(Flags::WIDE_CHAR);
+        if col == cols || (wide && col + 1 == cols && col > 0) {
+            row.wrapped = true;
+            rows.push(std::mem::replace(&mut row, Row::new(cols)));
+            col = 0;
+        }
+        if cursor_offset == Some(index) {
+            *cursor = Cursor {
+                row: rows.len(),
+                col: col.min(cols - 1),
+            };
+        }
+
+        if cell.flags.contains(Flags::WIDE_CHAR_SPACER) && col == 0 {
+            // The character it belonged to did not fit on a row on its own
+            continue;
+        }
+        if wide && cols == 1 {
+            cell = Cell::default();
+        }
+        row[col] = cell;
+        col += 1;
+    }
+    rows.push(row);
+}
+
 impl fmt::Display for Grid {
+    /// Format the grid as plain text, one line per row with trailing
+    /// blanks trimmed.
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        for row in &self.cells {
-            for cell in row {
-                write!(f, "{}", cell)?;
+        for row in &self.lines {
+            let mut line = String::new();
+            for cell in row.iter() {
+                if cell.flags.contains(Flags::WIDE_CHAR_SPACER) {
+                    continue;
+                }
+                line.push(cell.c);
+                line.extend(&cell.zerowidth);
             }
-            writeln!(f)?;
+            writeln!(f, "{}", line.trim_end())?;
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fill row 0 of the grid with `c` and scroll it into the history.
+    fn push_line(grid: &mut Grid, c: char) {
+        grid.cursor = Cursor::default();
+        grid.put_char(c, 1, &Cell::default());
+        grid.scroll_up(0..=grid.rows - 1, 1, &Cell::default());
+    }
+
+    #[test]
+    fn test_scrolled_lines_enter_history() {
+        let mut grid = Grid::new(2, 4, 10);
+        push_line(&mut grid, 'a');
+        push_line(&mut grid, 'b');
+
+        grid.scroll_display(2);
+        assert_eq!(grid.display_offset(), 2);
+        assert_eq!(grid.visible_row(0)[0].c, 'a');
+        assert_eq!(grid.visible_row(1)[0].c, 'b');
+    }
+
+    #[test]
+    fn test_history_is_bounded() {
+        let mut grid = Grid::new(2, 4, 3);
+        for c in ['a', 'b', 'c', 'd', 'e'] {
+            push_line(&mut grid, c);
+        }
+
+        grid.scroll_display(isize::MAX);
+        assert_eq!(grid.display_offset(), 3);
+        assert_eq!(grid.visible_row(0)[0].c, 'c');
+    }
+
+    #[test]
+    fn test_line_numbers_follow_scrolling() {
+        let mut grid = Grid::new(2, 4, 2);
+        push_line(&mut grid, 'a');
+        grid.scroll_display(1);
+        let line = grid.visible_line(0);
+        assert_eq!(grid.line(line).unwrap()[0].c, 'a');
+        grid.scroll_to_bottom();
+
+        push_line(&mut grid, 'b');
+        assert_eq!(grid.line(line).unwrap()[0].c, 'a');
+        assert_eq!(grid.visible_line(0), line + 2);
+
+        // Lines dropped from the history are gone
+        push_line(&mut grid, 'c');
+        assert_eq!(grid.line(line), None);
+    }
+
+    #[test]
+    fn test_scroll_display_is_clamped() {
+        let mut grid = Grid::new(2, 4, 10);
+        push_line(&mut grid, 'a');
+        grid.scroll_display(5);
+        assert_eq!(grid.display_offset(), 1);
+        grid.scroll_display(-5);
+        assert_eq!(grid.display_offset(), 0);
+    }
+
+    #[test]
+    fn test_view_stays_on_content_while_output_arrives() {
+        let mut grid = Grid::new(2, 4, 10);
+        push_line(&mut grid, 'a');
+        push_line(&mut grid, 'b');
+        grid.scroll_display(1);
+        assert_eq!(grid.visible_row(0)[0].c, 'b');
+
+        push_line(&mut grid, 'c');
+        assert_eq!(grid.display_offset(), 2);
+        assert_eq!(grid.visible_row(0)[0].c, 'b');
+    }
+
+    #[test]
+    fn test_region_scroll_does_not_enter_history() {
+        let mut grid = Grid::new(3, 4, 10);
+        grid.scroll_up(1..=2, 1, &Cell::default());
+        grid.delete_lines(1, 0..=2, &Cell::default());
+        grid.scroll_display(1);
+        assert_eq!(grid.display_offset(), 0);
+    }
+
+    #[test]
+    fn test_resize_columns() {
+        let mut grid = Grid::new(2, 4, 10);
+        grid.put_char('a', 1, &Cell::default());
+        grid.resize(2, 2);
+        assert_eq!(grid.visible_row(0).len(), 2);
+        assert_eq!(grid.visible_row(0)[0].c, 'a');
+
+        grid.resize(2, 6);
+        assert_eq!(grid.visible_row(1).len(), 6);
+    }
+
+    #[test]
+    fn test_shrink_moves_lines_above_cursor_into_history() {
+        let mut grid = Grid::new(4, 4, 10);
+        for (row, c) in ['a', 'b', 'c', 'd'].into_iter().enumerate() {
+            grid.cursor = Cursor { row, col: 0 };
+            grid.put_char(c, 1, &Cell::default());
+        }
+        grid.resize(2, 4);
+        assert_eq!(grid.cursor.row, 1);
+        assert_eq!(grid.visible_row(0)[0].c, 'c');
+
+        // Growing again brings the lines back from the history
+        grid.resize(4, 4);
+        assert_eq!(grid.cursor.row, 3);
+        assert_eq!(grid.visible_row(0)[0].c, 'a');
+    }
+
+    #[test]
+    fn test_shrink_drops_blank_lines_below_cursor() {
+        let mut grid = Grid::new(4, 4, 10);
+        grid.put_char('a', 1, &Cell::default());
+        grid.resize(2, 4);
+        assert_eq!(grid.cursor.row, 0);
+        assert_eq!(grid.visible_row(0)[0].c, 'a');
+        assert_eq!(grid.display_offset(), 0);
+    }
+
+    #[test]
+    fn test_reflow_moves_wide_char_to_next_row() {
+        let mut grid = Grid::new(2, 4, 0);
+        grid.cursor.col = 1;
+        grid.put_char('\u{4e2d}', 2, &Cell::default());
+        grid.resize(2, 2);
+        assert_eq!(grid.to_string(), "\n\u{4e2d}\n");
+        assert_eq!(grid.cursor, Cursor { row: 1, col: 0 });
+
+        // Widening joins the rows again without the padding cell
+        grid.resize(2, 4);
+        assert_eq!(grid.to_string(), " \u{4e2d}\n\n");
+    }
+
+    /// Write `text` from the cursor, wrapping at the right edge.
+    fn write_wrapped(grid: &mut Grid, text: &str) {
+        for c in text.chars() {
+            if grid.cursor.col == grid.cols {
+                grid.set_wrapped(grid.cursor.row);
+                grid.cursor = Cursor {
+                    row: grid.cursor.row + 1,
+                    col: 0,
+                };
+            }
+            grid.put_char(c, 1, &Cell::default());
+            grid.cursor.col += 1;
+        }
+        grid.cursor.col -= 1;
+    }
+
+    #[test]
+    fn test_reflow_wrapped_line() {
+        let mut grid = Grid::new(3, 4, 10);
+        write_wrapped(&mut grid, "abcdef");
+        grid.resize(3, 8);
+        assert_eq!(grid.to_string(), "abcdef\n\n\n");
+        assert_eq!(grid.cursor, Cursor { row: 0, col: 5 });
+
+        grid.resize(3, 3);
+        assert_eq!(grid.to_string(), "abc\ndef\n\n");
+        assert_eq!(grid.cursor, Cursor { row: 1, col: 2 });
+    }
+
+    #[test]
+    fn test_reflow_history() {
+        let mut grid = Grid::new(2, 4, 10);
+        write_wrapped(&mut grid, "abcdefgh");
+        grid.scroll_up(0..=1, 2, &Cell::default());
+        grid.cursor = Cursor::default();
+        grid.put_char('x', 1, &Cell::default());
+
+        // The wrapped line in the history is joined again
+        grid.resize(2, 8);
+        assert_eq!(grid.to_string(), "abcdefgh\nx\n");
+        assert_eq!(grid.display_offset(), 0);
+
+        // Narrowing splits it up across the history and the screen
+        grid.resize(3, 2);
+        assert_eq!(grid.to_string(), "ef\ngh\nx\n");
+        grid.scroll_display(2);
+        assert_eq!(grid.visible_row(0)[0].c, 'a');
+        assert_eq!(grid.visible_row(1)[0].c, 'c');
+    }
+}
@@ -0,0 +1,545 @@
+//! Encoding keyboard input into the bytes a terminal sends to the PTY.
+//!
+//! Named keys produce the same sequences as xterm. Modifiers are encoded
+//! as an extra parameter (`CSI 1;5C` for Ctrl+Right), and the sequences
+//! for cursor and keypad keys depend on the modes the application set.
+//! Ctrl turns characters into control characters and Alt either prefixes
+//! them with ESC or is left to the keyboard layout, as configured.
+//!
+//! Applications that enable xterm's modifyOtherKeys get modified keys
+//! that would otherwise be ambiguous as `CSI 27;modifiers;code ~`.
+//! Applications that enable the kitty keyboard protocol get keys encoded
+//! by `kitty.rs` instead where the protocol asks for it.
+
+use crate::kitty::{self, KeyboardFlags};
+use serde::Deserialize;
+use winit::event::{ElementState, KeyEvent};
+use winit::keyboard::{Key, KeyLocation, ModifiersState, NamedKey};
+use winit::platform::modifier_supplement::KeyEventExtModifierSupplement;
+
+/// What the Alt key does to character input.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AltKey {
+    /// Alt acts as Meta and prefixes the character with ESC
+    #[default]
+    Escape,
+    /// Alt is used by the keyboard layout to compose characters, which
+    /// are sent as they are
+    Compose,
+}
+
+/// Terminal modes that change what keys send.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct InputMode {
+    /// DECCKM: cursor keys send SS3 sequences
+    pub app_cursor: bool,
+    /// DECKPAM: the numeric keypad sends SS3 sequences
+    pub app_keypad: bool,
+    /// LNM: Enter sends CR LF
+    pub linefeed_newline: bool,
+    /// xterm modifyOtherKeys level (0 to 2)
+    pub modify_other_keys: u8,
+    /// Enhancements of the kitty keyboard protocol in effect
+    pub keyboard_flags: KeyboardFlags,
+}
+
+/// Whether a key was pressed, is auto-repeating or was released.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyEventKind {
+    Press,
+    Repeat,
+    Release,
+}
+
+/// A key event to encode.
+#[derive(Debug, Clone)]
+pub struct KeyInput {
+    /// The key with the keyboard layout and modifiers applied
+    pub key: Key,
+    /// The key with the keyboard layout applied but without modifiers,
+    /// e.g. `1` for Shift+1 on a US layout
+    pub unmodified: Key,
+    /// Where on the keyboard the key is
+    pub location: KeyLocation,
+    /// Press, repeat or release
+    pub kind: KeyEventKind,
+    /// Text the key produces, if any
+    pub text: Option<String>,
+}
+
+impl From<&KeyEvent> for KeyInput {
+    fn from(event: &KeyEvent) -> Self {
+        let kind = match (event.state, event.repeat) {
+            (ElementState::Released, _) => KeyEventKind::Release,
+            (ElementState::Pressed, true) => KeyEventKind::Repeat,
+            (ElementState::Pressed, false) => KeyEventKind::Press,
+        };
+        Self {
+            key: event.logical_key.clone(),
+            unmodified: event.key_without_modifiers(),
+            location: event.location,
+            kind,
+            text: event.text.as_ref().map(|text| text.to_string()),
+        }
+    }
+}
+
+/// How a named key is encoded.
+enum Encoding {
+    /// `CSI <letter>`, or `SS3 <letter>` where the mode asks for it
+    Letter(u8),
+    /// `CSI <number> ~`
+    Tilde(u8),
+}
+
+/// Encode a key event.
+///
+/// # Returns
+/// The bytes to write to the PTY, or `None` if the event sends nothing.
+pub fn encode_key(
+    input: &KeyInput,
+    mods: ModifiersState,
+    mode: InputMode,
+    alt_key: AltKey,
+) -> Option<Vec<u8>> {
+    if !kitty::uses_legacy_encoding(input, mods, mode.keyboard_flags) {
+        return kitty::encode(input, mods, mode.keyboard_flags);
+    }
+    if input.kind == KeyEventKind::Release {
+        return None;
+    }
+    encode_legacy(&input.key, input.location, mods, mode, alt_key)
+}
+
+/// Encode a key press the way xterm does.
+fn encode_legacy(
+    key: &Key,
+    location: KeyLocation,
+    mods: ModifiersState,
+    mode: InputMode,
+    alt_key: AltKey,
+) -> Option<Vec<u8>> {
+    if let Some(bytes) = modify_other_key(key, mods, mode.modify_other_keys) {
+        return Some(bytes);
+    }
+
+    let named = match key {
+        Key::Character(text) if location == KeyLocation::Numpad && mode.app_keypad => {
+            return keypad_key(text).map(|c| vec![0x1b, b'O', c]);
+        }
+        Key::Character(text) => {
+            let bytes = match control_char(text) {
+                Some(c) if mods.control_key() => vec![c],
+                _ => text.as_bytes().to_vec(),
+            };
+            return Some(alt_prefix(bytes, mods, alt_key));
+        }
+        Key::Named(named) => *named,
+        _ => return None,
+    };
+
+    let bytes = match named {
+        NamedKey::Enter if location == KeyLocation::Numpad && mode.app_keypad => b"\x1bOM".to_vec(),
+        NamedKey::Enter if mode.linefeed_newline => b"\r\n".to_vec(),
+        NamedKey::Enter => b"\r".to_vec(),
+        NamedKey::Tab if mods.shift_key() => b"\x1b[Z".to_vec(),
+        NamedKey::Tab => b"\t".to_vec(),
+        NamedKey::Space if mods.control_key() => vec![0],
+        NamedKey::Space => b" ".to_vec(),
+        NamedKey::Backspace if mods.control_key() => vec![0x08],
+        NamedKey::Backspace => vec![0x7f],
+        NamedKey::Escape => vec![0x1b],
+        _ => {
+            let encoding = named_encoding(named)?;
+            return Some(encode_sequence(
+                encoding,
+                modifier_param(mods),
+                mode.app_cursor,
+            ));
+        }
+    };
+    Some(alt_prefix(bytes, mods, alt_key))
+}
+
+/// Prefix `bytes` with ESC if Alt is held and acts as Meta.
+fn alt_prefix(mut bytes: Vec<u8>, mods: ModifiersState, alt_key: AltKey) -> Vec<u8> {
+    if mods.alt_key() && alt_key == AltKey::Escape {
+        bytes.insert(0, 0x1b);
+    }
+    bytes
+}
+
+/// Get the control character Ctrl plus `text` sends, if any.
+///
+/// Letters map to 0x01-0x1a and `@ [ \ ] ^ _` to the remaining C0 codes.
+/// The digits 2-8 produce the same codes as on a VT220 keyboard.
+fn control_char(text: &str) -> Option<u8> {
+    let &[c] = text.as_bytes() else {
+        return None;
+    };
+    let code = match c {
+        b'a'..=b'z' => c - b'a' + 1,
+        b'@' | b'A'..=b'Z' | b'[' | b'\\' | b']' | b'^' | b'_' => c & 0x1f,
+        b' ' | b'2' => 0,
+        b'3' => 0x1b,
+        b'4' => 0x1c,
+        b'5' => 0x1d,
+        b'6' | b'~' => 0x1e,
+        b'7' | b'/' => 0x1f,
+        b'8' | b'?' => 0x7f,
+        _ => return None,
+    };
+    Some(code)
+}
+
+/// Get the encoding of a cursor, editing or function key.
+fn named_encoding(key: NamedKey) -> Option<Encoding> {
+    let encoding = match key {
+        NamedKey::ArrowUp => Encoding::Letter(b'A'),
+        NamedKey::ArrowDown => Encoding::Letter(b'B'),
+        NamedKey::ArrowRight => Encoding::Letter(b'C'),
+        NamedKey::ArrowLeft => Encoding::Letter(b'D'),
+        NamedKey::End => Encoding::Letter(b'F'),
+        NamedKey::Home => Encoding::Letter(b'H'),
+        NamedKey::F1 => Encoding::Letter(b'P'),
+        NamedKey::F2 => Encoding::Letter(b'Q'),
+        NamedKey::F3 => Encoding::Letter(b'R'),
+        NamedKey::F4 => Encoding::Letter(b'S'),
+        NamedKey::Insert => Encoding::Tilde(2),
+        NamedKey::Delete => Encoding::Tilde(3),
+        NamedKey::PageUp => Encoding::Tilde(5),
+        NamedKey::PageDown => Encoding::Tilde(6),
+        NamedKey::F5 => Encoding::Tilde(15),
+        NamedKey::F6 => Encoding::Tilde(17),
+        NamedKey::F7 => Encoding::Tilde(18),
+        NamedKey::F8 => Encoding::Tilde(19),
+        NamedKey::F9 => Encoding::Tilde(20),
+        NamedKey::F10 => Encoding::Tilde(21),
+        NamedKey::F11 => Encoding::Tilde(23),
+        NamedKey::F12 => Encoding::Tilde(24),
+        _ => return None,
+    };
+    Some(encoding)
+}
+
+/// Build the escape sequence for a named key.
+///
+/// F1-F4 always use SS3 without modifiers, the cursor keys only in
+/// application cursor mode. With modifiers every key uses the CSI form.
+fn encode_sequence(encoding: Encoding, modifier: Option<u8>, app_cursor: bool) -> Vec<u8> {
+    match (encoding, modifier) {
+        (Encoding::Letter(c), None) if app_cursor || matches!(c, b'P'..=b'S') => {
+            vec![0x1b, b'O', c]
+        }
+        (Encoding::Letter(c), None) => vec![0x1b, b'[', c],
+        (Encoding::Letter(c), Some(m)) => format!("\x1b[1;{}{}", m, c as char).into_bytes(),
+        (Encoding::Tilde(n), None) => format!("\x1b[{}~", n).into_bytes(),
+        (Encoding::Tilde(n), Some(m)) => format!("\x1b[{};{}~", n, m).into_bytes(),
+    }
+}
+
+/// Encode a modified key as `CSI 27;modifiers;code ~` if the
+/// modifyOtherKeys `level` asks for it.
+///
+/// Level 1 only covers Ctrl combinations that have no control character
+/// of their own, such as Ctrl+Shift+letter, Ctrl+Enter or Ctrl plus a
+/// digit. The VT220 codes of Ctrl+2 to Ctrl+8 are not used here. Level
+/// 2 covers every character, Enter, Tab, Backspace and Escape with Ctrl,
+/// Alt or Super held.
+fn modify_other_key(key: &Key, mods: ModifiersState, level: u8) -> Option<Vec<u8>> {
+    let (c, has_control_char) = match key {
+        Key::Character(text) => {
+            let mut chars = text.chars();
+            let (Some(c), None) = (chars.next(), chars.next()) else {
+                return None;
+            };
+            let shifted_letter = mods.shift_key() && c.is_alphabetic();
+            let digit = c.is_ascii_digit();
+            (c, control_char(text).is_some() && !shifted_letter && !digit)
+        }
+        Key::Named(NamedKey::Enter) => ('\r', false),
+        Key::Named(NamedKey::Tab) => ('\t', false),
+        Key::Named(NamedKey::Escape) => ('\x1b', false),
+        Key::Named(NamedKey::Space) => (' ', true),
+        Key::Named(NamedKey::Backspace) => ('\x7f', true),
+        _ => return None,
+    };
+
+    let modify = match level {
+        1 => mods.control_key() && !has_control_char,
+        2 => mods.control_key() || mods.alt_key() || mods.super_key(),
+        _ => false,
+    };
+    if !modify {
+        return None;
+    }
+    let modifier = modifier_param(mods)?;
+    Some(format!("\x1b[27;{};{}~", modifier, c as u32).into_bytes())
+}
+
+/// Get the xterm modifier parameter: 1 plus a bit for each of Shift,
+/// Alt, Ctrl and Super. `None` if no modifier is held.
+pub(crate) fn modifier_param(mods: ModifiersState) -> Option<u8> {
+    let mut bits = 0;
+    if mods.shift_key() {
+        bits |= 1;
+    }
+    if mods.alt_key() {
+        bits |= 2;
+    }
+    if mods.control_key() {
+        bits |= 4;
+    }
+    if mods.super_key() {
+        bits |= 8;
+    }
+    (bits != 0).then_some(bits + 1)
+}
+
+/// Get the final byte of the SS3 sequence a keypad key sends in
+/// application keypad mode.
+fn keypad_key(text: &str) -> Option<u8> {
+    let c = match text {
+        "*" => b'j',
+        "+" => b'k',
+        "," => b'l',
+        "-" => b'm',
+        "." => b'n',
+        "/" => b'o',
+        "=" => b'X',
+        digit @ ("0" | "1" | "2" | "3" | "4" | "5" | "6" | "7" | "8" | "9") => {
+            b'p' + (digit.as_bytes()[0] - b'0')
+        }
+        _ => return None,
+    };
+    Some(c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn press(key: Key, location: KeyLocation) -> KeyInput {
+        KeyInput {
+            unmodified: key.clone(),
+            key,
+            location,
+            kind: KeyEventKind::Press,
+            text: None,
+        }
+    }
+
+    fn encode(key: NamedKey, mods: ModifiersState, mode: InputMode) -> Vec<u8> {
+        let input = press(Key::Named(key), KeyLocation::Standard);
+        encode_key(&input, mods, mode, AltKey::Escape).unwrap()
+    }
+
+    fn encode_char(text: &str, mods: ModifiersState, alt_key: AltKey) -> Vec<u8> {
+        let input = press(Key::Character(text.into()), KeyLocation::Standard);
+        encode_key(&input, mods, InputMode::default(), alt_key).unwrap()
+    }
+
+    #[test]
+    fn test_control_characters() {
+        let ctrl = ModifiersState::CONTROL;
+        assert_eq!(encode_char("c", ctrl, AltKey::Escape), b"\x03");
+        assert_eq!(encode_char("C", ctrl, AltKey::Escape), b"\x03");
+        assert_eq!(encode_char("@", ctrl, AltKey::Escape), b"\x00");
+        assert_eq!(encode_char("[", ctrl, AltKey::Escape), b"\x1b");
+        assert_eq!(encode_char("_", ctrl, AltKey::Escape), b"\x1f");
+        assert_eq!(
+            encode_char("\u{e9}", ctrl, AltKey::Escape),
+            "\u{e9}".as_bytes()
+        );
+        assert_eq!(encode(NamedKey::Space, ctrl, InputMode::default()), b"\x00");
+    }
+
+    #[test]
+    fn test_alt() {
+        let alt = ModifiersState::ALT;
+        assert_eq!(encode_char("b", alt, AltKey::Escape), b"\x1bb");
+        assert_eq!(encode_char("b", alt, AltKey::Compose), b"b");
+        assert_eq!(
+            encode_char("c", alt | ModifiersState::CONTROL, AltKey::Escape),
+            b"\x1b\x03"
+        );
+        assert_eq!(
+            encode(NamedKey::Backspace, alt, InputMode::default()),
+            b"\x1b\x7f"
+        );
+    }
+
+    #[test]
+    fn test_modify_other_keys() {
+        let encode_with = |text: &str, mods, level| {
+            let input = press(Key::Character(text.into()), KeyLocation::Standard);
+            let mode = InputMode {
+                modify_other_keys: level,
+                ..InputMode::default()
+            };
+            encode_key(&input, mods, mode, AltKey::Escape).unwrap()
+        };
+        let ctrl = ModifiersState::CONTROL;
+        let ctrl_shift = ModifiersState::CONTROL | ModifiersState::SHIFT;
+
+        assert_eq!(encode_with("A", ctrl_shift, 1), b"\x1b[27;6;65~");
+        assert_eq!(encode_with("1", ctrl, 1), b"\x1b[27;5;49~");
+        assert_eq!(encode_with("2", ctrl, 1), b"\x1b[27;5;50~");
+        assert_eq!(encode_with("0", ctrl, 1), b"\x1b[27;5;48~");
+        assert_eq!(encode_with("2", ctrl, 0), b"\x00");
+        assert_eq!(encode_with("a", ctrl, 1), b"\x01");
+        assert_eq!(encode_with("a", ModifiersState::ALT, 1), b"\x1ba");
+
+        assert_eq!(encode_with("a", ctrl, 2), b"\x1b[27;5;97~");
+        assert_eq!(encode_with("a", ModifiersState::ALT, 2), b"\x1b[27;3;97~");
+        assert_eq!(encode_with("A", ModifiersState::SHIFT, 2), b"A");
+
+        let tab = press(Key::Named(NamedKey::Tab), KeyLocation::Standard);
+        let mode = InputMode {
+            modify_other_keys: 1,
+            ..InputMode::default()
+        };
+        assert_eq!(
+            encode_key(&tab, ctrl, mode, AltKey::Escape).unwrap(),
+            b"\x1b[27;5;9~"
+        );
+    }
+
+    #[test]
+    fn test_cursor_keys() {
+        let none = ModifiersState::empty();
+        let app = InputMode {
+            app_cursor: true,
+            ..InputMode::default()
+        };
+        assert_eq!(
+            encode(NamedKey::ArrowUp, none, InputMode::default()),
+            b"\x1b[A"
+        );
+        assert_eq!(
+            encode(NamedKey::Home, none, InputMode::default()),
+            b"\x1b[H"
+        );
+        assert_eq!(encode(NamedKey::ArrowUp, none, app), b"\x1bOA");
+        assert_eq!(encode(NamedKey::End, none, app), b"\x1bOF");
+    }
+
+    #[test]
+    fn test_modifiers() {
+        let mode = InputMode {
+            app_cursor: true,
+            ..InputMode::default()
+        };
+        assert_eq!(
+            encode(NamedKey::ArrowRight, ModifiersState::CONTROL, mode),
+            b"\x1b[1;5C"
+        );
+        assert_eq!(
+            encode(
+                NamedKey::ArrowLeft,
+                ModifiersState::SHIFT | ModifiersState::ALT,
+                mode
+            ),
+            b"\x1b[1;4D"
+        );
+        assert_eq!(
+            encode(NamedKey::Delete, ModifiersState::CONTROL, mode),
+            b"\x1b[3;5~"
+        );
+        assert_eq!(
+            encode(NamedKey::F1, ModifiersState::SHIFT, mode),
+            b"\x1b[1;2P"
+        );
+        assert_eq!(
+            encode(NamedKey::Tab, ModifiersState::SHIFT, mode),
+            b"\x1b[Z"
+        );
+    }
+
+    #[test]
+    fn test_function_and_editing_keys() {
+        let none = ModifiersState::empty();
+        let mode = InputMode::default();
+        assert_eq!(encode(NamedKey::F1, none, mode), b"\x1bOP");
+        assert_eq!(encode(NamedKey::F4, none, mode), b"\x1bOS");
+        assert_eq!(encode(NamedKey::F5, none, mode), b"\x1b[15~");
+        assert_eq!(encode(NamedKey::F12, none, mode), b"\x1b[24~");
+        assert_eq!(encode(NamedKey::Insert, none, mode), b"\x1b[2~");
+        assert_eq!(encode(NamedKey::PageDown, none, mode), b"\x1b[6~");
+        assert_eq!(encode(NamedKey::Backspace, none, mode), b"\x7f");
+    }
+
+    #[test]
+    fn test_keypad() {
+        let none = ModifiersState::empty();
+        let app = InputMode {
+            app_keypad: true,
+            ..InputMode::default()
+        };
+        let encode_at = |key: Key, location, mode| {
+            encode_key(&press(key, location), none, mode, AltKey::Escape).unwrap()
+        };
+        let digit = Key::Character("5".into());
+        assert_eq!(
+            encode_at(digit.clone(), KeyLocation::Numpad, InputMode::default()),
+            b"5"
+        );
+        assert_eq!(
+            encode_at(digit.clone(), KeyLocation::Numpad, app),
+            b"\x1bOu"
+        );
+        assert_eq!(encode_at(digit, KeyLocation::Standard, app), b"5");
+        assert_eq!(
+            encode_at(Key::Named(NamedKey::Enter), KeyLocation::Numpad, app),
+            b"\x1bOM"
+        );
+    }
+
+    #[test]
+    fn test_key_release() {
+        let mut input = press(Key::Named(NamedKey::Escape), KeyLocation::Standard);
+        input.kind = KeyEventKind::Release;
+        let none = ModifiersState::empty();
+        assert_eq!(
+            encode_key(&input, none, InputMode::default(), AltKey::Escape),
+            None
+        );
+
+        // Releases are reported once the application asks for them
+        let mode = InputMode {
+            keyboard_flags: KeyboardFlags::DISAMBIGUATE | KeyboardFlags::REPORT_EVENT_TYPES,
+            ..InputMode::default()
+        };
+        assert_eq!(
+            encode_key(&input, none, mode, AltKey::Escape).unwrap(),
+            b"\x1b[27;1:3u"
+        );
+    }
+
+    #[test]
+    fn test_key_repeat() {
+        // Holding a key sends the same bytes as pressing it again
+        let keys = [
+            Key::Named(NamedKey::Backspace),
+            Key::Named(NamedKey::ArrowLeft),
+            Key::Character("a".into()),
+        ];
+        let none = ModifiersState::empty();
+        for key in keys {
+            let mut input = press(key, KeyLocation::Standard);
+            let pressed = encode_key(&input, none, InputMode::default(), AltKey::Escape);
+            input.kind = KeyEventKind::Repeat;
+            let repeated = encode_key(&input, none, InputMode::default(), AltKey::Escape);
+            assert!(pressed.is_some());
+            assert_eq!(repeated, pressed);
+        }
+    }
+
+    #[test]
+    fn test_unknown_key() {
+        let input = press(Key::Named(NamedKey::BrightnessUp), KeyLocation::Standard);
+        let none = ModifiersState::empty();
+        assert!(encode_key(&input, none, InputMode::default(), AltKey::Escape).is_none());
+    }
+}
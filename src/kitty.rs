@@ -0,0 +1,524 @@
+//! Key encoding for the kitty keyboard protocol.
+//!
+//! Applications opt in to the protocol's progressive enhancements by
+//! pushing a set of flags (`CSI > flags u`). Keys are then reported as
+//! `CSI code[:alternates] ; modifiers[:event] ; text u`, or in the legacy
+//! `CSI number ~` and `CSI 1;modifiers letter` forms for functional keys,
+//! so that combinations like Ctrl+I and Tab can be told apart.
+//!
+//! See <https://sw.kovidgoyal.net/kitty/keyboard-protocol/>.
+
+use crate::input::{KeyEventKind, KeyInput, modifier_param};
+use winit::keyboard::{Key, KeyLocation, ModifiersState, NamedKey};
+
+/// Progressive enhancement flags of the kitty keyboard protocol.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KeyboardFlags(u8);
+
+impl KeyboardFlags {
+    /// Report keys that are ambiguous in the legacy encoding as escape codes
+    pub const DISAMBIGUATE: Self = Self(1 << 0);
+    /// Report repeat and release events
+    pub const REPORT_EVENT_TYPES: Self = Self(1 << 1);
+    /// Report the shifted key in addition to the key code
+    pub const REPORT_ALTERNATE_KEYS: Self = Self(1 << 2);
+    /// Report every key, including text keys, as an escape code
+    pub const REPORT_ALL_KEYS: Self = Self(1 << 3);
+    /// Report the text a key produces along with its escape code
+    pub const REPORT_TEXT: Self = Self(1 << 4);
+
+    /// All flags defined by the protocol.
+    const ALL: Self = Self(0x1f);
+
+    /// Create flags from their numeric value, ignoring unknown bits.
+    pub fn from_bits(bits: u16) -> Self {
+        Self(bits as u8 & Self::ALL.0)
+    }
+
+    /// Get the numeric value of the flags.
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    /// Check whether no flag is set.
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Check whether all flags in `other` are set.
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl std::ops::BitOr for KeyboardFlags {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+/// Check whether a key keeps its legacy encoding under `flags`.
+///
+/// With no flags set every key does. Escape, keypad keys and keys that
+/// are ambiguous with modifiers only switch to escape codes when asked
+/// to disambiguate: text keys with Ctrl, Alt or Super, and Enter, Tab
+/// and Backspace with any modifier. Unless all keys are reported, other
+/// text keys still send their text. Lock and modifier keys are only
+/// reported together with all other keys.
+pub fn uses_legacy_encoding(input: &KeyInput, mods: ModifiersState, flags: KeyboardFlags) -> bool {
+    if flags.is_empty() {
+        return true;
+    }
+    if flags.contains(KeyboardFlags::REPORT_ALL_KEYS) {
+        return false;
+    }
+    let disambiguate = flags.contains(KeyboardFlags::DISAMBIGUATE);
+    let command_mods = mods.control_key() || mods.alt_key() || mods.super_key();
+    match &input.key {
+        _ if input.location == KeyLocation::Numpad => !disambiguate,
+        Key::Character(_) | Key::Named(NamedKey::Space) => !(disambiguate && command_mods),
+        Key::Named(NamedKey::Enter | NamedKey::Tab | NamedKey::Backspace) => {
+            !disambiguate || mods.is_empty()
+        }
+        Key::Named(NamedKey::Escape) => !disambiguate,
+        Key::Named(named) => functional_key(*named, input.location).is_none(),
+        _ => true,
+    }
+}
+
+/// Encode a key event as an escape code.
+///
+/// # Returns
+/// The escape code, or `None` if the event is not reported under `flags`.
+pub fn encode(input: &KeyInput, mods: ModifiersState, flags: KeyboardFlags) -> Option<Vec<u8>> {
+    let event = match input.kind {
+        KeyEventKind::Press => 1,
+        _ if !flags.contains(KeyboardFlags::REPORT_EVENT_TYPES) => {
+            if input.kind == KeyEventKind::Release {
+                return None;
+            }
+            1
+        }
+        KeyEventKind::Repeat => 2,
+        KeyEventKind::Release => 3,
+    };
+
+    let (code, terminator) = match &input.key {
+        Key::Named(named) if is_modifier(*named) => {
+            if !flags.contains(KeyboardFlags::REPORT_ALL_KEYS) {
+                return None;
+            }
+            functional_key(*named, input.location)?
+        }
+        Key::Named(NamedKey::Space) => (' ' as u32, 'u'),
+        Key::Named(named) => functional_key(*named, input.location)?,
+        Key::Character(text) if input.location == KeyLocation::Numpad => (keypad_code(text)?, 'u'),
+        Key::Character(text) => (
+            text_code(&input.unmodified).or_else(|| text_code_of(text))?,
+            'u',
+        ),
+        _ => return None,
+    };
+
+    let mut key = code.to_string();
+    if flags.contains(KeyboardFlags::REPORT_ALTERNATE_KEYS)
+        && mods.shift_key()
+        && let Key::Character(text) = &input.key
+        && let Some(shifted) = single_char(text)
+        && shifted as u32 != code
+    {
+        key.push_str(&format!(":{}", shifted as u32));
+    }
+
+    let text = match &input.text {
+        Some(text)
+            if flags.contains(KeyboardFlags::REPORT_ALL_KEYS | KeyboardFlags::REPORT_TEXT)
+                && event != 3
+                && !text.chars().any(char::is_control) =>
+        {
+            let codepoints: Vec<String> = text.chars().map(|c| (c as u32).to_string()).collect();
+            Some(codepoints.join(":"))
+        }
+        _ => None,
+    };
+
+    // The protocol uses the xterm modifier parameter, 1 for none
+    let modifiers = modifier_param(mods).unwrap_or(1);
+    let mut sequence = String::from("\x1b[");
+    let has_params = modifiers != 1 || event != 1 || text.is_some();
+    if terminator == 'u' || terminator == '~' || has_params {
+        sequence.push_str(&key);
+    }
+    if has_params {
+        sequence.push_str(&format!(";{}", modifiers));
+        if event != 1 {
+            sequence.push_str(&format!(":{}", event));
+        }
+    }
+    if let Some(text) = text {
+        sequence.push_str(&format!(";{}", text));
+    }
+    sequence.push(terminator);
+    Some(sequence.into_bytes())
+}
+
+/// Check whether a key is a modifier or lock key.
+fn is_modifier(key: NamedKey) -> bool {
+    matches!(
+        key,
+        NamedKey::Shift
+            | NamedKey::Control
+            | NamedKey::Alt
+            | NamedKey::Super
+            | NamedKey::Hyper
+            | NamedKey::Meta
+            | NamedKey::CapsLock
+            | NamedKey::NumLock
+            | NamedKey::ScrollLock
+    )
+}
+
+/// Get the key code of a functional key together with the final byte of
+/// its escape code.
+///
+/// Keys with a `CSI number ~` or `CSI 1;modifiers letter` form in the
+/// legacy encoding keep it; all others use `CSI code u`.
+fn functional_key(key: NamedKey, location: KeyLocation) -> Option<(u32, char)> {
+    let right = location == KeyLocation::Right;
+    if location == KeyLocation::Numpad {
+        let code = match key {
+            NamedKey::Enter => 57414,
+            NamedKey::ArrowLeft => 57417,
+            NamedKey::ArrowRight => 57418,
+            NamedKey::ArrowUp => 57419,
+            NamedKey::ArrowDown => 57420,
+            NamedKey::PageUp => 57421,
+            NamedKey::PageDown => 57422,
+            NamedKey::Home => 57423,
+            NamedKey::End => 57424,
+            NamedKey::Insert => 57425,
+            NamedKey::Delete => 57426,
+            NamedKey::Clear => 57427,
+            _ => return None,
+        };
+        return Some((code, 'u'));
+    }
+
+    let tilde = match key {
+        NamedKey::Insert => Some(2),
+        NamedKey::Delete => Some(3),
+        NamedKey::PageUp => Some(5),
+        NamedKey::PageDown => Some(6),
+        NamedKey::F3 => Some(13),
+        NamedKey::F5 => Some(15),
+        NamedKey::F6 => Some(17),
+        NamedKey::F7 => Some(18),
+        NamedKey::F8 => Some(19),
+        NamedKey::F9 => Some(20),
+        NamedKey::F10 => Some(21),
+        NamedKey::F11 => Some(23),
+        NamedKey::F12 => Some(24),
+        _ => None,
+    };
+    if let Some(number) = tilde {
+        return Some((number, '~'));
+    }
+
+    let letter = match key {
+        NamedKey::ArrowUp => Some('A'),
+        NamedKey::ArrowDown => Some('B'),
+        NamedKey::ArrowRight => Some('C'),
+        NamedKey::ArrowLeft => Some('D'),
+        NamedKey::End => Some('F'),
+        NamedKey::Home => Some('H'),
+        NamedKey::F1 => Some('P'),
+        NamedKey::F2 => Some('Q'),
+        NamedKey::F4 => Some('S'),
+        _ => None,
+    };
+    if let Some(letter) = letter {
+        return Some((1, letter));
+    }
+
+    let code = match key {
+        NamedKey::Escape => 27,
+        NamedKey::Enter => 13,
+        NamedKey::Tab => 9,
+        NamedKey::Backspace => 127,
+        NamedKey::CapsLock => 57358,
+        NamedKey::ScrollLock => 57359,
+        NamedKey::NumLock => 57360,
+        NamedKey::PrintScreen => 57361,
+        NamedKey::Pause => 57362,
+        NamedKey::ContextMenu => 57363,
+        NamedKey::Shift if right => 57447,
+        NamedKey::Shift => 57441,
+        NamedKey::Control if right => 57448,
+        NamedKey::Control => 57442,
+        NamedKey::Alt if right => 57449,
+        NamedKey::Alt => 57443,
+        NamedKey::Super if right => 57450,
+        NamedKey::Super => 57444,
+        NamedKey::Hyper if right => 57451,
+        NamedKey::Hyper => 57445,
+        NamedKey::Meta if right => 57452,
+        NamedKey::Meta => 57446,
+        _ => function_key_code(key)?,
+    };
+    Some((code, 'u'))
+}
+
+/// Get the key code of F13-F35.
+fn function_key_code(key: NamedKey) -> Option<u32> {
+    const KEYS: [NamedKey; 23] = [
+        NamedKey::F13,
+        NamedKey::F14,
+        NamedKey::F15,
+        NamedKey::F16,
+        NamedKey::F17,
+        NamedKey::F18,
+        NamedKey::F19,
+        NamedKey::F20,
+        NamedKey::F21,
+        NamedKey::F22,
+        NamedKey::F23,
+        NamedKey::F24,
+        NamedKey::F25,
+        NamedKey::F26,
+        NamedKey::F27,
+        NamedKey::F28,
+        NamedKey::F29,
+        NamedKey::F30,
+        NamedKey::F31,
+        NamedKey::F32,
+        NamedKey::F33,
+        NamedKey::F34,
+        NamedKey::F35,
+    ];
+    let index = KEYS.iter().position(|k| *k == key)?;
+    Some(57376 + index as u32)
+}
+
+/// Get the key code of a keypad key that produces text.
+fn keypad_code(text: &str) -> Option<u32> {
+    let code = match text {
+        digit @ ("0" | "1" | "2" | "3" | "4" | "5" | "6" | "7" | "8" | "9") => {
+            57399 + (digit.as_bytes()[0] - b'0') as u32
+        }
+        "." => 57409,
+        "/" => 57410,
+        "*" => 57411,
+        "-" => 57412,
+        "+" => 57413,
+        "=" => 57415,
+        "," => 57416,
+        _ => return None,
+    };
+    Some(code)
+}
+
+/// Get the key code of a text key: its lowercase character.
+fn text_code(key: &Key) -> Option<u32> {
+    match key {
+        Key::Character(text) => text_code_of(text),
+        Key::Named(NamedKey::Space) => Some(' ' as u32),
+        _ => None,
+    }
+}
+
+/// Get the lowercase code point of a single-character string.
+fn text_code_of(text: &str) -> Option<u32> {
+    let c = single_char(text)?;
+    let mut lower = c.to_lowercase();
+    match (lower.next(), lower.next()) {
+        (Some(lower), None) => Some(lower as u32),
+        _ => Some(c as u32),
+    }
+}
+
+/// Get the only character of a string.
+fn single_char(text: &str) -> Option<char> {
+    let mut chars = text.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Some(c),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DISAMBIGUATE: KeyboardFlags = KeyboardFlags::DISAMBIGUATE;
+
+    fn input(key: Key, unmodified: Key, kind: KeyEventKind) -> KeyInput {
+        KeyInput {
+            key,
+            unmodified,
+            location: KeyLocation::Standard,
+            kind,
+            text: None,
+        }
+    }
+
+    fn press(key: NamedKey) -> KeyInput {
+        input(Key::Named(key), Key::Named(key), KeyEventKind::Press)
+    }
+
+    fn press_char(text: &str, unmodified: &str) -> KeyInput {
+        input(
+            Key::Character(text.into()),
+            Key::Character(unmodified.into()),
+            KeyEventKind::Press,
+        )
+    }
+
+    fn encode_str(input: &KeyInput, mods: ModifiersState, flags: KeyboardFlags) -> String {
+        String::from_utf8(encode(input, mods, flags).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_disambiguate() {
+        let none = ModifiersState::empty();
+        let ctrl = ModifiersState::CONTROL;
+        assert_eq!(
+            encode_str(&press(NamedKey::Escape), none, DISAMBIGUATE),
+            "\x1b[27u"
+        );
+        assert_eq!(
+            encode_str(&press_char("i", "i"), ctrl, DISAMBIGUATE),
+            "\x1b[105;5u"
+        );
+        assert_eq!(
+            encode_str(
+                &press_char("I", "i"),
+                ctrl | ModifiersState::SHIFT,
+                DISAMBIGUATE
+            ),
+            "\x1b[105;6u"
+        );
+        assert_eq!(
+            encode_str(&press(NamedKey::Tab), ctrl, DISAMBIGUATE),
+            "\x1b[9;5u"
+        );
+
+        // Text and unmodified Enter, Tab and Backspace stay as they are
+        assert!(uses_legacy_encoding(
+            &press_char("a", "a"),
+            none,
+            DISAMBIGUATE
+        ));
+        assert!(uses_legacy_encoding(
+            &press_char("A", "a"),
+            ModifiersState::SHIFT,
+            DISAMBIGUATE
+        ));
+        assert!(uses_legacy_encoding(
+            &press(NamedKey::Tab),
+            none,
+            DISAMBIGUATE
+        ));
+        assert!(!uses_legacy_encoding(
+            &press_char("a", "a"),
+            ctrl,
+            DISAMBIGUATE
+        ));
+    }
+
+    #[test]
+    fn test_functional_keys() {
+        let none = ModifiersState::empty();
+        let ctrl = ModifiersState::CONTROL;
+        assert_eq!(
+            encode_str(&press(NamedKey::ArrowUp), none, DISAMBIGUATE),
+            "\x1b[A"
+        );
+        assert_eq!(
+            encode_str(&press(NamedKey::ArrowUp), ctrl, DISAMBIGUATE),
+            "\x1b[1;5A"
+        );
+        assert_eq!(
+            encode_str(&press(NamedKey::F3), none, DISAMBIGUATE),
+            "\x1b[13~"
+        );
+        assert_eq!(
+            encode_str(&press(NamedKey::Delete), ctrl, DISAMBIGUATE),
+            "\x1b[3;5~"
+        );
+        assert_eq!(
+            encode_str(&press(NamedKey::F13), none, DISAMBIGUATE),
+            "\x1b[57376u"
+        );
+    }
+
+    #[test]
+    fn test_event_types() {
+        let flags = DISAMBIGUATE | KeyboardFlags::REPORT_EVENT_TYPES;
+        let none = ModifiersState::empty();
+        let key = Key::Named(NamedKey::ArrowLeft);
+        let repeat = input(key.clone(), key.clone(), KeyEventKind::Repeat);
+        let release = input(key.clone(), key, KeyEventKind::Release);
+        assert_eq!(encode_str(&repeat, none, flags), "\x1b[1;1:2D");
+        assert_eq!(encode_str(&release, none, flags), "\x1b[1;1:3D");
+
+        // Without the flag releases are not reported and repeats look
+        // like presses
+        assert_eq!(encode(&release, none, DISAMBIGUATE), None);
+        assert_eq!(encode_str(&repeat, none, DISAMBIGUATE), "\x1b[D");
+    }
+
+    #[test]
+    fn test_report_all_keys() {
+        let flags = DISAMBIGUATE | KeyboardFlags::REPORT_ALL_KEYS;
+        let shift = ModifiersState::SHIFT;
+        assert_eq!(
+            encode_str(&press_char("a", "a"), ModifiersState::empty(), flags),
+            "\x1b[97u"
+        );
+        assert_eq!(
+            encode_str(&press_char("A", "a"), shift, flags),
+            "\x1b[97;2u"
+        );
+        assert_eq!(
+            encode_str(&press(NamedKey::Shift), shift, flags),
+            "\x1b[57441;2u"
+        );
+        assert_eq!(encode(&press(NamedKey::Shift), shift, DISAMBIGUATE), None);
+    }
+
+    #[test]
+    fn test_alternate_keys_and_text() {
+        let flags = DISAMBIGUATE
+            | KeyboardFlags::REPORT_ALL_KEYS
+            | KeyboardFlags::REPORT_ALTERNATE_KEYS
+            | KeyboardFlags::REPORT_TEXT;
+        let mut key = press_char("!", "1");
+        key.text = Some("!".into());
+        assert_eq!(
+            encode_str(&key, ModifiersState::SHIFT, flags),
+            "\x1b[49:33;2;33u"
+        );
+
+        let mut key = press_char("a", "a");
+        key.text = Some("a".into());
+        assert_eq!(
+            encode_str(&key, ModifiersState::empty(), flags),
+            "\x1b[97;1;97u"
+        );
+    }
+
+    #[test]
+    fn test_keypad() {
+        let none = ModifiersState::empty();
+        let mut key = press_char("5", "5");
+        key.location = KeyLocation::Numpad;
+        assert!(!uses_legacy_encoding(&key, none, DISAMBIGUATE));
+        assert_eq!(encode_str(&key, none, DISAMBIGUATE), "\x1b[57404u");
+
+        let mut enter = press(NamedKey::Enter);
+        enter.location = KeyLocation::Numpad;
+        assert_eq!(encode_str(&enter, none, DISAMBIGUATE), "\x1b[57414u");
+    }
+}
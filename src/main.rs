@@ -1,29 +1,69 @@
+use arboard::Clipboard;
 use softbuffer::{Context, Surface};
 use std::num::NonZeroU32;
 use std::rc::Rc;
+use std::time::{Duration, Instant};
 use winit::{
     application::ApplicationHandler,
     dpi::LogicalSize,
-    event::{ElementState, KeyEvent, WindowEvent},
-    event_loop::{ActiveEventLoop, EventLoopProxy},
-    keyboard::{Key, NamedKey},
+    event::{ElementState, MouseScrollDelta, WindowEvent},
+    event_loop::{ActiveEventLoop, ControlFlow, EventLoopProxy},
+    keyboard::{Key, ModifiersState, NamedKey},
     window::{Window, WindowAttributes, WindowId},
 };
 
 mod ansi;
+mod bindings;
 mod buffer;
+mod config;
 mod font;
 mod grid;
+mod input;
+mod kitty;
+mod mouse;
+mod paste;
 mod pty;
+mod render;
+mod selection;
+mod term;
+use bindings::{Action, KeyBinding};
 use buffer::TerminalBuffer;
+use config::{Config, ExitAction, ShellConfig};
+use portable_pty::ExitStatus;
 use font::FontRenderer;
-use pty::PtySession;
+use input::{KeyEventKind, KeyInput};
+use mouse::{MouseButton, MouseEncoding, MouseEvent, MouseEventKind, MouseMode, MouseProtocol};
+use pty::{PtySession, PtySize};
+use selection::{Point, SelectionKind};
+use term::CursorStyle;
+
+/// Lines scrolled per mouse wheel step.
+const WHEEL_SCROLL_LINES: f64 = 3.0;
+
+/// Font size in pixels at startup and after `ResetFontSize`.
+const DEFAULT_FONT_SIZE: f32 = 14.0;
+/// Font size change per `IncreaseFontSize` / `DecreaseFontSize`.
+const FONT_SIZE_STEP: f32 = 1.0;
+/// Smallest and largest font size the bindings can select.
+const FONT_SIZE_RANGE: (f32, f32) = (6.0, 72.0);
+
+/// Longest time between clicks that count as a double or triple click.
+const MULTI_CLICK_INTERVAL: Duration = Duration::from_millis(400);
+
+/// Time a blinking cursor stays shown or hidden.
+const CURSOR_BLINK_INTERVAL: Duration = Duration::from_millis(530);
+
+/// Refresh rate assumed when the monitor does not report one, in mHz.
+const DEFAULT_REFRESH_RATE: u32 = 60_000;
 
 /// Custom event type for triggering redraws.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 enum AppEvent {
-    /// New PTY output available, trigger redraw
+    /// New PTY output available, trigger redraw. Sent once until the
+    /// output is handled, however many reads it took
     NewOutput,
+    /// The shell exited with the given status
+    ChildExited(ExitStatus),
 }
 
 /// Application state with proper softbuffer resource management.
@@ -39,20 +79,605 @@ struct Application {
     buffer: Option<TerminalBuffer>,
     font: Option<FontRenderer>,
     proxy: Option<EventLoopProxy<AppEvent>>,
+    config: Config,
+    /// Active key bindings: the defaults merged with the configured ones
+    bindings: Vec<KeyBinding>,
+    /// System clipboard, if one is available
+    clipboard: Option<Clipboard>,
+    /// Currently held keyboard modifiers
+    modifiers: ModifiersState,
+    /// Pixel scroll distance not yet converted into whole lines
+    pending_scroll: f64,
+    /// Last known pointer position in window pixels
+    mouse_position: (f64, f64),
+    /// Mouse button currently held down
+    mouse_button: Option<MouseButton>,
+    /// Cell of the last mouse report, to report motion only when the
+    /// pointer moves to another cell
+    last_mouse_cell: Option<(usize, usize)>,
+    /// Time and cell of the last click, and how many clicks in a row
+    /// landed there
+    last_click: Option<(Instant, Point, usize)>,
+    /// Whether the left button is held down to extend a selection
+    selecting: bool,
+    /// The shell exited and the window was kept open
+    exited: bool,
+    /// Why the shell could not be started, shown instead of the terminal
+    pty_error: Option<String>,
+    /// Pasted text waiting for the user to confirm it
+    pending_paste: Option<String>,
+    /// Why the last paste could not be sent, shown until a key is pressed
+    paste_error: Option<String>,
+    /// Whether the window has keyboard focus
+    focused: bool,
+    /// Whether a blinking cursor is in its shown phase
+    cursor_blink_on: bool,
+    /// When to toggle the cursor next; `None` while it does not blink
+    next_blink: Option<Instant>,
+    /// Shortest time between two redraws for output, one monitor frame
+    frame_interval: Duration,
+    /// When the window was last drawn
+    last_frame: Instant,
+    /// When to draw output that arrived too soon after the last frame
+    redraw_at: Option<Instant>,
+    /// Size last given to the PTY, in cells and pixels
+    pty_size: Option<PtySize>,
+}
+
+impl Application {
+    /// Create the application state. The window, terminal and shell are
+    /// set up once the event loop resumes.
+    fn new(config: Config, proxy: Option<EventLoopProxy<AppEvent>>) -> Self {
+        Self {
+            window: None,
+            context: None,
+            surface: None,
+            pty: None,
+            buffer: None,
+            font: None,
+            proxy,
+            bindings: bindings::with_defaults(&config.key_bindings),
+            clipboard: None,
+            config,
+            modifiers: ModifiersState::empty(),
+            pending_scroll: 0.0,
+            mouse_position: (0.0, 0.0),
+            mouse_button: None,
+            last_mouse_cell: None,
+            exited: false,
+            pty_error: None,
+            pending_paste: None,
+            paste_error: None,
+            last_click: None,
+            selecting: false,
+            focused: true,
+            cursor_blink_on: true,
+            next_blink: None,
+            frame_interval: Duration::from_secs(1000) / DEFAULT_REFRESH_RATE,
+            last_frame: Instant::now(),
+            redraw_at: None,
+            pty_size: None,
+        }
+    }
+
+    /// Scroll the view into the scrollback history by `lines` (positive
+    /// values scroll back) and redraw.
+    fn scroll_display(&self, lines: isize) {
+        if let (Some(window), Some(buffer)) = (&self.window, &self.buffer) {
+            buffer.with_terminal_mut(|term| term.scroll_display(lines));
+            window.request_redraw();
+        }
+    }
+
+    /// Return the view to the bottom of the screen.
+    fn scroll_to_bottom(&self) {
+        if let (Some(window), Some(buffer)) = (&self.window, &self.buffer) {
+            let scrolled = buffer.with_terminal_mut(|term| {
+                let scrolled = term.grid().display_offset() != 0;
+                term.scroll_to_bottom();
+                scrolled
+            });
+            if scrolled {
+                window.request_redraw();
+            }
+        }
+    }
+
+    /// Resize the terminal to fit the window and tell the shell about it.
+    ///
+    /// The grid is resized even while no shell runs, so one started later
+    /// gets the current size. The shell is told whenever the size in
+    /// cells or in pixels changes, e.g. after a font change that keeps
+    /// the number of rows and columns.
+    fn resize(&mut self, width: u32, height: u32) {
+        let (Some(buffer), Some(font)) = (&self.buffer, &self.font) else {
+            return;
+        };
+
+        let (rows, cols) = render::grid_size(width as usize, height as usize, font);
+        buffer.with_terminal_mut(|term| {
+            let grid = term.grid();
+            if grid.rows != rows || grid.cols != cols {
+                log::info!("Terminal resized to {}x{}", cols, rows);
+                term.resize(rows, cols);
+            }
+        });
+
+        let size = self.shell_size();
+        if let Some(pty) = &self.pty
+            && self.pty_size != Some(size)
+        {
+            pty.resize(size);
+            self.pty_size = Some(size);
+        }
+    }
+
+    /// Change the font size and resize the terminal to match.
+    fn set_font_size(&mut self, size: f32) {
+        let size = size.clamp(FONT_SIZE_RANGE.0, FONT_SIZE_RANGE.1);
+        if self.font.as_ref().is_some_and(|font| font.font_size() == size) {
+            return;
+        }
+        log::info!("Setting font size to {}", size);
+        self.font = Some(FontRenderer::with_size(size));
+
+        if let Some(window) = self.window.clone() {
+            let size = window.inner_size();
+            self.resize(size.width, size.height);
+            window.request_redraw();
+        }
+    }
+
+    /// Paste the clipboard contents, or the primary selection if
+    /// `primary` is set.
+    fn paste(&mut self, primary: bool) {
+        let Some(clipboard) = &mut self.clipboard else {
+            return;
+        };
+        let text = if primary {
+            get_primary_selection(clipboard)
+        } else {
+            clipboard.get_text()
+        };
+        match text {
+            Ok(text) => self.paste_text(&text),
+            Err(e) => log::warn!("Failed to read the clipboard: {}", e),
+        }
+    }
+
+    /// Write pasted text to the PTY, bracketed if the application asked
+    /// for it.
+    ///
+    /// Without bracketed paste, text that would run commands is held back
+    /// and a confirmation message is shown instead, if configured.
+    fn paste_text(&mut self, text: &str) {
+        let (Some(window), Some(buffer), Some(_)) = (&self.window, &self.buffer, &self.pty) else {
+            return;
+        };
+        let bracketed = buffer.with_terminal(|term| term.bracketed_paste());
+        if self.config.confirm_paste && !bracketed && paste::needs_confirmation(text) {
+            log::debug!("Asking for confirmation before pasting");
+            self.pending_paste = Some(text.to_string());
+            window.request_redraw();
+            return;
+        }
+        self.write_paste(text);
+    }
+
+    /// Send pasted text to the shell, or show why it could not be sent,
+    /// e.g. because it is too large or the shell is not reading.
+    fn write_paste(&mut self, text: &str) {
+        let (Some(buffer), Some(pty)) = (&self.buffer, &self.pty) else {
+            return;
+        };
+        let bracketed = buffer.with_terminal(|term| term.bracketed_paste());
+        match pty.write_all(&paste::encode_paste(text, bracketed)) {
+            Ok(()) => self.scroll_to_bottom(),
+            Err(e) => {
+                log::warn!("Failed to paste: {}", e);
+                self.paste_error = Some(e.to_string());
+                if let Some(window) = &self.window {
+                    window.request_redraw();
+                }
+            }
+        }
+    }
+
+    /// Handle a key press while a paste waits for confirmation: Enter
+    /// pastes the text and Escape drops it. Other keys are ignored.
+    fn confirm_paste(&mut self, key: &Key) {
+        let confirmed = match key {
+            Key::Named(NamedKey::Enter) => true,
+            Key::Named(NamedKey::Escape) => false,
+            _ => return,
+        };
+        let Some(text) = self.pending_paste.take() else {
+            return;
+        };
+        if confirmed {
+            self.write_paste(&text);
+        }
+        if let Some(window) = &self.window {
+            window.request_redraw();
+        }
+    }
+
+    /// Start the shell in a new PTY session.
+    ///
+    /// If that fails, the error is shown in the window with the choice to
+    /// retry or to start the fallback shell.
+    fn start_shell(&mut self, shell: &ShellConfig) {
+        let (Some(buffer), Some(proxy)) = (&self.buffer, &self.proxy) else {
+            return;
+        };
+        let size = self.shell_size();
+
+        // Do not hand the new shell the modes the last program left on
+        buffer.with_terminal_mut(|term| term.reset_modes());
+
+        // Initialize PTY session with buffer callback
+        log::info!("Initializing PTY session");
+        let buffer = buffer.clone();
+        let proxy = proxy.clone();
+        let exit_proxy = proxy.clone();
+        let callback = std::sync::Arc::new(std::sync::Mutex::new(Box::new(move |data: &[u8]| {
+            // Tell the UI about new data unless it has yet to handle
+            // earlier output
+            if buffer.write(data) {
+                let _ = proxy.send_event(AppEvent::NewOutput);
+            }
+        }) as Box<dyn Fn(&[u8]) + Send + Sync>));
+        let on_exit: pty::ExitCallback = Box::new(move |status| {
+            let _ = exit_proxy.send_event(AppEvent::ChildExited(status));
+        });
+        match PtySession::with_output_callback(size, shell, callback, Some(on_exit)) {
+            Ok(pty) => {
+                self.pty = Some(pty);
+                self.pty_size = Some(size);
+                self.pty_error = None;
+                self.exited = false;
+            }
+            Err(e) => {
+                log::error!("Failed to start the shell: {}", e);
+                self.pty_error = Some(e.to_string());
+            }
+        }
+
+        if let Some(window) = &self.window {
+            window.request_redraw();
+        }
+    }
+
+    /// Redraw for new output, waiting until a frame after the last one
+    /// so a flood of output is drawn at most once per monitor refresh.
+    fn schedule_redraw(&mut self) {
+        if self.redraw_at.is_some() {
+            return;
+        }
+        let next_frame = self.last_frame + self.frame_interval;
+        if Instant::now() >= next_frame {
+            if let Some(window) = &self.window {
+                window.request_redraw();
+            }
+        } else {
+            self.redraw_at = Some(next_frame);
+        }
+    }
+
+    /// Close the window or show how the shell exited, as configured.
+    fn shell_exited(&mut self, status: &ExitStatus, event_loop: &ActiveEventLoop) {
+        log::info!("Shell exited: {}", status);
+        self.pty = None;
+
+        let on_exit = self.config.shell.on_exit;
+        if on_exit == ExitAction::Close {
+            event_loop.exit();
+            return;
+        }
+        self.exited = true;
+
+        if let (Some(window), Some(buffer)) = (&self.window, &self.buffer) {
+            let mut banner = format!("\x1b[0m\r\n{}\r\n", pty::exit_message(status));
+            if on_exit == ExitAction::Restart {
+                banner.push_str("Press any key to restart the shell\r\n");
+            }
+            buffer.print(&banner);
+            buffer.with_terminal_mut(|term| term.scroll_to_bottom());
+            window.request_redraw();
+        }
+    }
+
+    /// Handle a key press while the shell could not be started: Enter
+    /// tries again, S starts the fallback shell and Escape quits.
+    fn handle_pty_error_key(&mut self, key: &Key, event_loop: &ActiveEventLoop) {
+        match key {
+            Key::Named(NamedKey::Enter) => {
+                let shell = self.config.shell.clone();
+                self.start_shell(&shell);
+            }
+            Key::Character(c) if c.eq_ignore_ascii_case("s") => {
+                let shell = ShellConfig {
+                    program: Some(pty::FALLBACK_SHELL.to_string()),
+                    ..ShellConfig::default()
+                };
+                self.start_shell(&shell);
+            }
+            Key::Named(NamedKey::Escape) => event_loop.exit(),
+            _ => {}
+        }
+    }
+
+    /// Put the selected text on the clipboard, or into the primary
+    /// selection if `primary` is set.
+    fn copy_selection(&mut self, primary: bool) {
+        let (Some(clipboard), Some(buffer)) = (&mut self.clipboard, &self.buffer) else {
+            return;
+        };
+        let Some(text) = buffer.with_terminal(|term| term.selection_text()) else {
+            return;
+        };
+        let result = if primary {
+            set_primary_selection(clipboard, text)
+        } else {
+            clipboard.set_text(text)
+        };
+        if let Err(e) = result {
+            log::warn!("Failed to copy the selection: {}", e);
+        }
+    }
+
+    /// Get the point in the grid under the mouse pointer.
+    fn mouse_point(&self) -> Option<Point> {
+        let (buffer, font) = (self.buffer.as_ref()?, self.font.as_ref()?);
+        let (x, y) = self.mouse_position;
+        Some(buffer.with_terminal(|term| {
+            let (row, col) = render::cell_at(x, y, font, term.grid());
+            Point::new(term.grid().visible_line(row), col)
+        }))
+    }
+
+    /// Start selecting text at the mouse pointer.
+    ///
+    /// Repeated clicks on the same cell select words, then lines; with
+    /// Alt held a block of cells is selected.
+    fn start_selection(&mut self) {
+        let Some(point) = self.mouse_point() else {
+            return;
+        };
+        let now = Instant::now();
+        let clicks = match self.last_click {
+            Some((time, last_point, clicks))
+                if last_point == point && now - time < MULTI_CLICK_INTERVAL =>
+            {
+                clicks % 3 + 1
+            }
+            _ => 1,
+        };
+        self.last_click = Some((now, point, clicks));
+
+        let kind = match clicks {
+            _ if self.modifiers.alt_key() => SelectionKind::Block,
+            1 => SelectionKind::Simple,
+            2 => SelectionKind::Word,
+            _ => SelectionKind::Line,
+        };
+        if let (Some(window), Some(buffer)) = (&self.window, &self.buffer) {
+            let separators = &self.config.word_separators;
+            buffer.with_terminal_mut(|term| term.start_selection(kind, point, separators));
+            window.request_redraw();
+        }
+        self.selecting = true;
+    }
+
+    /// Extend the selection to the mouse pointer.
+    fn update_selection(&self) {
+        let Some(point) = self.mouse_point() else {
+            return;
+        };
+        if let (Some(window), Some(buffer)) = (&self.window, &self.buffer) {
+            let separators = &self.config.word_separators;
+            buffer.with_terminal_mut(|term| term.update_selection(point, separators));
+            window.request_redraw();
+        }
+    }
+
+    /// Run the action of a key binding.
+    fn perform_action(&mut self, action: Action, event_loop: &ActiveEventLoop) {
+        log::debug!("Performing {:?}", action);
+        let page = self.screen_rows() as isize;
+        let font_size = self.font.as_ref().map_or(DEFAULT_FONT_SIZE, |font| font.font_size());
+        match action {
+            Action::Copy => self.copy_selection(false),
+            Action::Paste => self.paste(false),
+            Action::ScrollPageUp => self.scroll_display(page),
+            Action::ScrollPageDown => self.scroll_display(-page),
+            Action::ScrollLineUp => self.scroll_display(1),
+            Action::ScrollLineDown => self.scroll_display(-1),
+            Action::ScrollToTop => self.scroll_display(isize::MAX),
+            Action::ScrollToBottom => self.scroll_to_bottom(),
+            Action::IncreaseFontSize => self.set_font_size(font_size + FONT_SIZE_STEP),
+            Action::DecreaseFontSize => self.set_font_size(font_size - FONT_SIZE_STEP),
+            Action::ResetFontSize => self.set_font_size(DEFAULT_FONT_SIZE),
+            Action::Quit => event_loop.exit(),
+            Action::SendBytes(bytes) => {
+                if let Some(pty) = &self.pty {
+                    match pty.write_all(&bytes) {
+                        Ok(()) => self.scroll_to_bottom(),
+                        Err(e) => log::warn!("Failed to send {:?} to PTY: {}", bytes, e),
+                    }
+                }
+            }
+            Action::None => {}
+        }
+    }
+
+    /// Get the mouse protocol if mouse events go to the application: it
+    /// asked for them and Shift is not held. Shift leaves the mouse to
+    /// the terminal.
+    fn mouse_reporting(&self) -> Option<MouseProtocol> {
+        if self.modifiers.shift_key() {
+            return None;
+        }
+        let protocol = self.buffer.as_ref()?.with_terminal(|term| term.mouse_protocol());
+        (protocol.mode != MouseMode::None).then_some(protocol)
+    }
+
+    /// Report a mouse event at the pointer position to the application.
+    ///
+    /// Returns false if mouse events are not reported, in which case the
+    /// terminal handles the event itself.
+    fn report_mouse(&mut self, kind: MouseEventKind) -> bool {
+        let Some(protocol) = self.mouse_reporting() else {
+            return false;
+        };
+        let (Some(buffer), Some(pty), Some(font)) = (&self.buffer, &self.pty, &self.font) else {
+            return false;
+        };
+
+        let (x, y) = self.mouse_position;
+        let (row, col) = buffer.with_terminal(|term| render::cell_at(x, y, font, term.grid()));
+        let moved_within_cell = matches!(kind, MouseEventKind::Motion(_))
+            && protocol.encoding != MouseEncoding::SgrPixels
+            && self.last_mouse_cell == Some((row, col));
+        self.last_mouse_cell = Some((row, col));
+        if moved_within_cell {
+            return true;
+        }
+
+        let event = MouseEvent {
+            kind,
+            row,
+            col,
+            x: (x.max(0.0) as usize).saturating_sub(render::PADDING),
+            y: (y.max(0.0) as usize).saturating_sub(render::PADDING),
+            mods: self.modifiers,
+        };
+        if let Some(report) = mouse::encode_mouse(&event, protocol)
+            && let Err(e) = pty.write_all(&report)
+        {
+            log::warn!("Failed to send mouse report: {}", e);
+        }
+        true
+    }
+
+    /// Get the cursor style in effect: the one requested by the
+    /// application, or the configured default.
+    fn cursor_style(&self) -> CursorStyle {
+        self.buffer
+            .as_ref()
+            .and_then(|buffer| buffer.with_terminal(|term| term.cursor_style()))
+            .unwrap_or_else(|| self.config.cursor_style())
+    }
+
+    /// Show a blinking cursor right away, e.g. after a key press, and
+    /// keep it shown for a full blink interval.
+    fn reset_cursor_blink(&mut self) {
+        if self.next_blink.is_some() {
+            self.next_blink = Some(Instant::now() + CURSOR_BLINK_INTERVAL);
+        }
+        if !self.cursor_blink_on {
+            self.cursor_blink_on = true;
+            if let Some(window) = &self.window {
+                window.request_redraw();
+            }
+        }
+    }
+
+    /// Start or stop the blink timer after drawing. The cursor only
+    /// blinks while its style says so and the window has focus, so no
+    /// wakeups are scheduled otherwise.
+    fn schedule_cursor_blink(&mut self, now: Instant) {
+        if self.focused && self.cursor_style().blinking {
+            self.next_blink.get_or_insert(now + CURSOR_BLINK_INTERVAL);
+        } else {
+            self.next_blink = None;
+            self.cursor_blink_on = true;
+        }
+    }
+
+    /// Size of the PTY for the shell: the current grid size, in cells
+    /// and in pixels.
+    fn shell_size(&self) -> PtySize {
+        let (Some(buffer), Some(font)) = (&self.buffer, &self.font) else {
+            return PtySize::default();
+        };
+        let (rows, cols) = buffer.with_terminal(|term| (term.grid().rows, term.grid().cols));
+        PtySize {
+            rows: rows as u16,
+            cols: cols as u16,
+            pixel_width: (cols * font.char_width()) as u16,
+            pixel_height: (rows * font.char_height()) as u16,
+        }
+    }
+
+    /// Number of screen rows, used for page-wise scrolling.
+    fn screen_rows(&self) -> usize {
+        self.buffer
+            .as_ref()
+            .map_or(0, |buffer| buffer.with_terminal(|term| term.grid().rows))
+    }
 }
 
 impl ApplicationHandler<AppEvent> for Application {
     fn user_event(&mut self, event_loop: &ActiveEventLoop, event: AppEvent) {
         match event {
             AppEvent::NewOutput => {
-                // Trigger redraw when new PTY output is available
-                if let Some(window) = &self.window {
-                    window.request_redraw();
+                // Output arriving from now on sends another event
+                if let Some(buffer) = &self.buffer {
+                    buffer.take_dirty();
+                }
+
+                // Send replies to terminal queries (e.g. cursor position reports)
+                if let (Some(buffer), Some(pty)) = (&self.buffer, &self.pty) {
+                    let responses = buffer.take_responses();
+                    if !responses.is_empty()
+                        && let Err(e) = pty.write_all(&responses)
+                    {
+                        log::warn!("Failed to send terminal replies: {}", e);
+                    }
                 }
+
+                if let (Some(window), Some(buffer)) = (&self.window, &self.buffer) {
+                    let title = buffer.title();
+                    if !title.is_empty() && title != window.title() {
+                        window.set_title(&title);
+                    }
+                }
+                self.schedule_redraw();
             }
+            AppEvent::ChildExited(status) => self.shell_exited(&status, event_loop),
         }
     }
 
+    fn about_to_wait(&mut self, event_loop: &ActiveEventLoop) {
+        let now = Instant::now();
+
+        // Draw output that was held back once its frame is due
+        if let Some(redraw_at) = self.redraw_at
+            && now >= redraw_at
+        {
+            self.redraw_at = None;
+            if let Some(window) = &self.window {
+                window.request_redraw();
+            }
+        }
+
+        // Toggle a blinking cursor; drawing it schedules the next toggle
+        if let Some(next_blink) = self.next_blink
+            && now >= next_blink
+        {
+            self.next_blink = None;
+            self.cursor_blink_on = !self.cursor_blink_on;
+            if let Some(window) = &self.window {
+                window.request_redraw();
+            }
+        }
+
+        // Sleep until the next held back frame or blink
+        let wake_at = self.redraw_at.into_iter().chain(self.next_blink).min();
+        event_loop.set_control_flow(wake_at.map_or(ControlFlow::Wait, ControlFlow::WaitUntil));
+    }
+
     fn resumed(&mut self, event_loop: &ActiveEventLoop) {
         // Same implementation as above
         let window_attributes = WindowAttributes::default()
@@ -64,6 +689,7 @@ impl ApplicationHandler<AppEvent> for Application {
                 log::info!("Window created successfully");
 
                 let window = Rc::new(window);
+                self.frame_interval = frame_interval(&window);
 
                 // Create softbuffer context and surface
                 let context = Context::new(window.clone())
@@ -77,25 +703,25 @@ impl ApplicationHandler<AppEvent> for Application {
 
                 // Initialize font renderer
                 log::info!("Initializing font renderer");
-                let font = FontRenderer::with_size(14.0);
+                let font = FontRenderer::with_size(DEFAULT_FONT_SIZE);
+
+                // Size the terminal to fit the window
+                let size = self.window.as_ref().unwrap().inner_size();
+                let (rows, cols) =
+                    render::grid_size(size.width as usize, size.height as usize, &font);
                 self.font = Some(font);
 
+                match Clipboard::new() {
+                    Ok(clipboard) => self.clipboard = Some(clipboard),
+                    Err(e) => log::warn!("Clipboard not available: {}", e),
+                }
+
                 // Initialize terminal buffer
-                log::info!("Initializing terminal buffer");
-                let buffer = TerminalBuffer::new();
-                let buffer_clone = buffer.clone();
-                self.buffer = Some(buffer);
-
-                // Initialize PTY session with buffer callback
-                log::info!("Initializing PTY session");
-                let proxy = self.proxy.clone().unwrap();
-                let callback = std::sync::Arc::new(std::sync::Mutex::new(Box::new(move |data: &[u8]| {
-                    buffer_clone.write(data);
-                    // Trigger redraw when new data arrives
-                    let _ = proxy.send_event(AppEvent::NewOutput);
-                }) as Box<dyn Fn(&[u8]) + Send + Sync>));
-                let pty = PtySession::with_output_callback(Some(callback));
-                self.pty = Some(pty);
+                log::info!("Initializing {}x{} terminal buffer", cols, rows);
+                self.buffer = Some(TerminalBuffer::new(rows, cols, self.config.scrollback_lines));
+
+                let shell = self.config.shell.clone();
+                self.start_shell(&shell);
 
                 // Request initial redraw
                 self.window.as_ref().unwrap().request_redraw();
@@ -118,54 +744,187 @@ impl ApplicationHandler<AppEvent> for Application {
                 log::info!("Close requested");
                 event_loop.exit();
             }
-            WindowEvent::KeyboardInput {
-                event: KeyEvent { logical_key, state, repeat, .. },
-                ..
-            } => {
-                // Only handle key press events (not release or repeat)
-                if state != ElementState::Pressed || repeat {
+            WindowEvent::KeyboardInput { event, .. } => {
+                let input = KeyInput::from(&event);
+
+                if self.pty_error.is_some() {
+                    if input.kind == KeyEventKind::Press {
+                        self.handle_pty_error_key(&input.key, event_loop);
+                    }
                     return;
                 }
 
-                // Check if Escape key was pressed
-                if logical_key == Key::Named(NamedKey::Escape) {
-                    log::info!("Escape key pressed, exiting...");
-                    event_loop.exit();
-                } else if let Some(pty) = &self.pty {
-                    // Handle special keys
-                    match &logical_key {
-                        Key::Named(NamedKey::Enter) => {
-                            // Send carriage return for Enter key
-                            pty.write(b'\r');
-                            log::debug!("Sent Enter key to PTY");
-                        }
-                        Key::Named(NamedKey::Backspace) => {
-                            // Send backspace for Backspace key
-                            pty.write(0x08); // ASCII backspace
-                            log::debug!("Sent Backspace key to PTY");
+                if self.pending_paste.is_some() {
+                    if input.kind == KeyEventKind::Press {
+                        self.confirm_paste(&input.key);
+                    }
+                    return;
+                }
+
+                // Any key dismisses a failed paste message
+                if self.paste_error.is_some() {
+                    if input.kind == KeyEventKind::Press {
+                        self.paste_error = None;
+                        if let Some(window) = &self.window {
+                            window.request_redraw();
                         }
-                        Key::Named(NamedKey::Tab) => {
-                            // Send tab for Tab key
-                            pty.write(b'\t');
-                            log::debug!("Sent Tab key to PTY");
+                    }
+                    return;
+                }
+
+                let mode = self
+                    .buffer
+                    .as_ref()
+                    .map_or_else(Default::default, |buffer| buffer.with_terminal(|term| term.input_mode()));
+                let modify_other_keys = mode.modify_other_keys > 0;
+                if let Some(action) =
+                    bindings::find_action(&self.bindings, &input.key, self.modifiers, modify_other_keys)
+                        .cloned()
+                {
+                    if input.kind != KeyEventKind::Release {
+                        self.perform_action(action, event_loop);
+                    }
+                    return;
+                }
+
+                if self.exited {
+                    if input.kind == KeyEventKind::Press
+                        && self.config.shell.on_exit == ExitAction::Restart
+                    {
+                        let shell = self.config.shell.clone();
+                        self.start_shell(&shell);
+                    }
+                    return;
+                }
+
+                if let Some(pty) = &self.pty {
+                    match input::encode_key(&input, self.modifiers, mode, self.config.alt_key) {
+                        Some(bytes) if pty.flow_control(&bytes) => {}
+                        Some(bytes) => {
+                            match pty.write_all(&bytes) {
+                                Ok(()) => log::debug!("Sent {:?} to PTY", input.key),
+                                Err(e) => log::warn!("Dropped {:?}: {}", input.key, e),
+                            }
                         }
-                        Key::Character(c) => {
-                            // Send character input
-                            pty.write_str(c.as_str());
-                            log::debug!("Sent '{}' to PTY", c);
+                        None => {
+                            log::debug!("Unhandled key: {:?}", input.key);
+                            return;
                         }
-                        _ => {
-                            log::debug!("Unhandled key: {:?}", logical_key);
+                    }
+
+                    // Typing returns the view to the bottom of the screen
+                    if input.kind != KeyEventKind::Release {
+                        self.scroll_to_bottom();
+                        self.reset_cursor_blink();
+                    }
+                }
+            }
+            WindowEvent::Focused(focused) => {
+                self.focused = focused;
+                self.cursor_blink_on = true;
+                if let Some(window) = &self.window {
+                    window.request_redraw();
+                }
+            }
+            WindowEvent::ModifiersChanged(modifiers) => {
+                self.modifiers = modifiers.state();
+            }
+            WindowEvent::CursorMoved { position, .. } => {
+                self.mouse_position = (position.x, position.y);
+                if !self.report_mouse(MouseEventKind::Motion(self.mouse_button)) && self.selecting {
+                    self.update_selection();
+                }
+            }
+            WindowEvent::MouseInput { state, button, .. } => {
+                let left = button == winit::event::MouseButton::Left;
+                let middle = button == winit::event::MouseButton::Middle;
+                let button = match button {
+                    winit::event::MouseButton::Left => MouseButton::Left,
+                    winit::event::MouseButton::Middle => MouseButton::Middle,
+                    winit::event::MouseButton::Right => MouseButton::Right,
+                    _ => return,
+                };
+                let kind = match state {
+                    ElementState::Pressed => {
+                        self.mouse_button = Some(button);
+                        MouseEventKind::Press(button)
+                    }
+                    ElementState::Released => {
+                        if self.mouse_button == Some(button) {
+                            self.mouse_button = None;
                         }
+                        MouseEventKind::Release(button)
+                    }
+                };
+                if self.report_mouse(kind) {
+                    return;
+                }
+                if middle && state == ElementState::Pressed {
+                    self.paste(true);
+                    return;
+                }
+                if !left {
+                    return;
+                }
+
+                // Without mouse reporting the left button selects text
+                match state {
+                    ElementState::Pressed => self.start_selection(),
+                    ElementState::Released if self.selecting => {
+                        self.selecting = false;
+                        self.copy_selection(true);
+                    }
+                    ElementState::Released => {}
+                }
+            }
+            WindowEvent::MouseWheel { delta, .. } => {
+                let char_height = self.font.as_ref().map_or(1, |font| font.char_height());
+                // Applications get one wheel event per notch
+                let reporting = self.mouse_reporting().is_some();
+                let notch_lines = if reporting { 1.0 } else { WHEEL_SCROLL_LINES };
+                let lines = match delta {
+                    MouseScrollDelta::LineDelta(_, y) => y as f64 * notch_lines,
+                    MouseScrollDelta::PixelDelta(position) => {
+                        position.y / char_height as f64
+                    }
+                };
+
+                // Keep fractional scroll amounts from touchpads for later
+                self.pending_scroll += lines;
+                let whole = self.pending_scroll.trunc();
+                self.pending_scroll -= whole;
+                if reporting {
+                    let button = if whole > 0.0 {
+                        MouseButton::WheelUp
+                    } else {
+                        MouseButton::WheelDown
+                    };
+                    for _ in 0..whole.abs() as usize {
+                        self.report_mouse(MouseEventKind::Press(button));
                     }
+                } else if whole != 0.0 {
+                    self.scroll_display(whole as isize);
                 }
             }
-            WindowEvent::Resized(_) => {
+            WindowEvent::Resized(size) => {
+                self.resize(size.width, size.height);
                 if let Some(window) = &self.window {
                     window.request_redraw();
                 }
             }
             WindowEvent::RedrawRequested => {
+                self.last_frame = Instant::now();
+                self.schedule_cursor_blink(self.last_frame);
+                let cursor_style = self.cursor_style();
+                let focused = self.focused;
+                let cursor_blink_on = self.cursor_blink_on || !cursor_style.blinking;
+                let message = match (&self.pty_error, &self.pending_paste, &self.paste_error) {
+                    (Some(error), _, _) => Some(pty_error_message(error)),
+                    (None, Some(text), _) => Some(paste::confirmation_message(text)),
+                    (None, None, Some(error)) => Some(paste::error_message(error)),
+                    (None, None, None) => None,
+                };
+
                 // Draw terminal buffer to window
                 if let (Some(window), Some(surface), Some(buffer), Some(font)) =
                     (&self.window, &mut self.surface, &self.buffer, &self.font)
@@ -182,33 +941,29 @@ impl ApplicationHandler<AppEvent> for Application {
                     let mut buffer_surface = surface.buffer_mut().expect("Failed to get buffer");
 
                     // Fill with dark background (ARGB format)
-                    buffer_surface.fill(0xff181818);
-
-                    // Get buffer content and split into lines
-                    let content = buffer.content();
-                    let lines = font::split_lines(&content);
-
-                    let width_val = width.get() as usize;
-                    let height_val = height.get() as usize;
-
-                    // Calculate visible lines
-                    let char_height = font.char_height();
-                    let visible_lines = height_val / char_height;
-
-                    // Use a bright green color for text (ARGB)
-                    let text_color = 0xff00ff00;
-
-                    // Render the text lines
-                    font.render_lines(
-                        lines.into_iter(),
-                        8, // Left padding
-                        8, // Top padding
-                        &mut buffer_surface,
-                        width_val,
-                        height_val,
-                        text_color,
-                        visible_lines,
-                    );
+                    buffer_surface.fill(render::BACKGROUND);
+
+                    // Draw the terminal grid cell by cell
+                    let mut frame = render::Frame {
+                        pixels: &mut buffer_surface,
+                        width: width.get() as usize,
+                        height: height.get() as usize,
+                    };
+                    buffer.with_terminal(|term| {
+                        render::draw_grid(&mut frame, font, term.grid(), term.selection());
+                        if term.cursor_visible() && (cursor_blink_on || !focused) {
+                            render::draw_cursor(
+                                &mut frame,
+                                font,
+                                term.grid(),
+                                cursor_style.shape,
+                                focused,
+                            );
+                        }
+                    });
+                    if let Some(message) = &message {
+                        render::draw_message_box(&mut frame, font, message);
+                    }
 
                     buffer_surface.present().expect("Failed to present buffer");
                 }
@@ -218,6 +973,71 @@ impl ApplicationHandler<AppEvent> for Application {
     }
 }
 
+/// Get the time between two frames of the monitor showing the window.
+fn frame_interval(window: &Window) -> Duration {
+    let refresh_rate = window
+        .current_monitor()
+        .and_then(|monitor| monitor.refresh_rate_millihertz())
+        .filter(|&rate| rate > 0)
+        .unwrap_or(DEFAULT_REFRESH_RATE);
+    log::debug!("Redrawing output at most {} times per second", refresh_rate / 1000);
+    Duration::from_secs(1000) / refresh_rate
+}
+
+/// Get the lines of the message shown when the shell could not be
+/// started.
+fn pty_error_message(error: &str) -> Vec<String> {
+    vec![
+        "The shell could not be started:".to_string(),
+        String::new(),
+        format!("  {}", error),
+        String::new(),
+        format!(
+            "Enter: retry   S: start {}   Escape: quit",
+            pty::FALLBACK_SHELL
+        ),
+    ]
+}
+
+/// Put text into the primary selection, which X11 applications paste
+/// with the middle mouse button.
+#[cfg(all(
+    unix,
+    not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+))]
+fn set_primary_selection(clipboard: &mut Clipboard, text: String) -> Result<(), arboard::Error> {
+    use arboard::{LinuxClipboardKind, SetExtLinux};
+    clipboard.set().clipboard(LinuxClipboardKind::Primary).text(text)
+}
+
+/// Other platforms have no primary selection.
+#[cfg(not(all(
+    unix,
+    not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+)))]
+fn set_primary_selection(_clipboard: &mut Clipboard, _text: String) -> Result<(), arboard::Error> {
+    Ok(())
+}
+
+/// Get the text in the primary selection.
+#[cfg(all(
+    unix,
+    not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+))]
+fn get_primary_selection(clipboard: &mut Clipboard) -> Result<String, arboard::Error> {
+    use arboard::{GetExtLinux, LinuxClipboardKind};
+    clipboard.get().clipboard(LinuxClipboardKind::Primary).text()
+}
+
+/// Other platforms have no primary selection.
+#[cfg(not(all(
+    unix,
+    not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+)))]
+fn get_primary_selection(_clipboard: &mut Clipboard) -> Result<String, arboard::Error> {
+    Err(arboard::Error::ContentNotAvailable)
+}
+
 fn main() {
     // Initialize logger
     env_logger::init();
@@ -228,15 +1048,106 @@ fn main() {
     // Get proxy for sending custom events
     let proxy = event_loop.create_proxy();
 
+    let config = Config::load();
+
     // Create and run application
-    let mut app = Application {
-        window: None,
-        context: None,
-        surface: None,
-        pty: None,
-        buffer: None,
-        font: None,
-        proxy: Some(proxy),
-    };
+    let mut app = Application::new(config, Some(proxy));
     event_loop.run_app(&mut app).unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Create the application with a terminal but without a window or a
+    /// shell.
+    fn application() -> Application {
+        let mut app = Application::new(Config::default(), None);
+        app.font = Some(FontRenderer::new());
+        app.buffer = Some(TerminalBuffer::new(24, 80, 0));
+        app
+    }
+
+    /// Get the window size that fits `rows` x `cols` cells.
+    fn window_size(app: &Application, rows: usize, cols: usize) -> (u32, u32) {
+        let font = app.font.as_ref().unwrap();
+        let width = cols * font.char_width() + 2 * render::PADDING;
+        let height = rows * font.char_height() + 2 * render::PADDING;
+        (width as u32, height as u32)
+    }
+
+    fn grid_size(app: &Application) -> (usize, usize) {
+        let buffer = app.buffer.as_ref().unwrap();
+        buffer.with_terminal(|term| (term.grid().rows, term.grid().cols))
+    }
+
+    #[test]
+    fn test_resize_without_shell() {
+        // The shell could not be started
+        let mut app = application();
+        app.pty_error = Some("failed to start fish".to_string());
+
+        let (width, height) = window_size(&app, 30, 100);
+        app.resize(width, height);
+        assert_eq!(grid_size(&app), (30, 100));
+    }
+
+    #[test]
+    fn test_restart_uses_new_size() {
+        // The shell exited and its banner is held
+        let mut app = application();
+        app.exited = true;
+
+        let (width, height) = window_size(&app, 40, 120);
+        app.resize(width, height);
+        let size = app.shell_size();
+        assert_eq!((size.rows, size.cols), (40, 120));
+    }
+
+    #[test]
+    fn test_font_change_reports_pixel_size() {
+        let mut app = application();
+        let shell = ShellConfig {
+            program: Some("sleep".to_string()),
+            args: vec!["5".to_string()],
+            ..ShellConfig::default()
+        };
+        let output: pty::OutputCallback = std::sync::Arc::new(std::sync::Mutex::new(Box::new(|_| {})));
+        let size = app.shell_size();
+        app.pty = Some(PtySession::with_output_callback(size, &shell, output, None).unwrap());
+        app.pty_size = Some(size);
+
+        // A bigger font in a bigger window keeps the grid at 24x80
+        app.font = Some(FontRenderer::with_size(DEFAULT_FONT_SIZE * 2.0));
+        let (width, height) = window_size(&app, 24, 80);
+        app.resize(width, height);
+        assert_eq!(grid_size(&app), (24, 80));
+
+        let font = app.font.as_ref().unwrap();
+        let reported = app.pty_size.unwrap();
+        assert_eq!(reported.pixel_width as usize, 80 * font.char_width());
+        assert_eq!(reported.pixel_height as usize, 24 * font.char_height());
+    }
+
+    #[test]
+    fn test_cursor_blinks_only_when_focused() {
+        let mut app = application();
+        let now = Instant::now();
+
+        // A steady cursor schedules no wakeups
+        app.focused = true;
+        app.schedule_cursor_blink(now);
+        assert_eq!(app.next_blink, None);
+
+        app.config.cursor_blinking = true;
+        app.schedule_cursor_blink(now);
+        assert_eq!(app.next_blink, Some(now + CURSOR_BLINK_INTERVAL));
+
+        // Losing focus stops the timer and shows the cursor
+        app.focused = false;
+        app.cursor_blink_on = false;
+        app.schedule_cursor_blink(now);
+        assert_eq!(app.next_blink, None);
+        assert!(app.cursor_blink_on);
+    }
+}
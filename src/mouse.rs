@@ -0,0 +1,269 @@
+//! Encoding mouse events into the reports a terminal sends to the PTY.
+//!
+//! Applications choose which events they want with DECSET 9 (X10,
+//! presses only), 1000 (presses and releases), 1002 (also motion while a
+//! button is held) or 1003 (all motion). The report format defaults to
+//! xterm's `CSI M` with coordinates encoded as single bytes and can be
+//! switched to SGR (1006), urxvt (1015) or SGR with pixel coordinates
+//! (1016).
+
+use winit::keyboard::ModifiersState;
+
+/// Largest coordinate the default encoding can report; coordinates are
+/// sent as a single byte offset by 32.
+const MAX_DEFAULT_COORDINATE: usize = 255 - 32;
+
+/// Which mouse events the application wants reported.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum MouseMode {
+    /// No reporting; the mouse is handled by the terminal
+    #[default]
+    None,
+    /// Button presses only (DECSET 9)
+    X10,
+    /// Button presses and releases (DECSET 1000)
+    Normal,
+    /// Presses, releases and motion while a button is held (DECSET 1002)
+    ButtonEvent,
+    /// Presses, releases and all motion (DECSET 1003)
+    AnyEvent,
+}
+
+/// How mouse reports are formatted.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum MouseEncoding {
+    /// `CSI M` followed by three bytes offset by 32
+    #[default]
+    Default,
+    /// `CSI < button ; col ; row M` or `m` for releases (DECSET 1006)
+    Sgr,
+    /// `CSI button ; col ; row M` with the button offset by 32
+    /// (DECSET 1015)
+    Urxvt,
+    /// Like `Sgr` but with pixel coordinates (DECSET 1016)
+    SgrPixels,
+}
+
+/// The mouse reporting settings of the terminal.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MouseProtocol {
+    /// Events to report
+    pub mode: MouseMode,
+    /// Format of the reports
+    pub encoding: MouseEncoding,
+}
+
+/// A mouse button, with the wheel counting as two buttons.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MouseButton {
+    Left,
+    Middle,
+    Right,
+    WheelUp,
+    WheelDown,
+}
+
+impl MouseButton {
+    /// Check whether the button is a wheel direction. Wheel "buttons"
+    /// are never released.
+    pub fn is_wheel(self) -> bool {
+        matches!(self, MouseButton::WheelUp | MouseButton::WheelDown)
+    }
+
+    /// Get the button number used in reports.
+    fn code(self) -> u32 {
+        match self {
+            MouseButton::Left => 0,
+            MouseButton::Middle => 1,
+            MouseButton::Right => 2,
+            MouseButton::WheelUp => 64,
+            MouseButton::WheelDown => 65,
+        }
+    }
+}
+
+/// What happened to the mouse.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MouseEventKind {
+    /// A button was pressed or the wheel turned
+    Press(MouseButton),
+    /// A button was released
+    Release(MouseButton),
+    /// The mouse moved, with the button held down, if any
+    Motion(Option<MouseButton>),
+}
+
+/// A mouse event to encode.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MouseEvent {
+    /// Press, release or motion
+    pub kind: MouseEventKind,
+    /// Row of the cell under the pointer (0-based)
+    pub row: usize,
+    /// Column of the cell under the pointer (0-based)
+    pub col: usize,
+    /// Pointer position in pixels relative to the top-left of the grid
+    pub x: usize,
+    /// Pointer position in pixels relative to the top-left of the grid
+    pub y: usize,
+    /// Modifiers held during the event
+    pub mods: ModifiersState,
+}
+
+/// Encode a mouse event as a report, or return `None` if the event is not
+/// reported in the current mode or cannot be encoded.
+pub fn encode_mouse(event: &MouseEvent, protocol: MouseProtocol) -> Option<Vec<u8>> {
+    let reported = match (protocol.mode, event.kind) {
+        (MouseMode::None, _) => false,
+        (MouseMode::X10, kind) => matches!(kind, MouseEventKind::Press(_)),
+        (_, MouseEventKind::Press(_)) => true,
+        (_, MouseEventKind::Release(button)) => !button.is_wheel(),
+        (MouseMode::Normal, MouseEventKind::Motion(_)) => false,
+        (MouseMode::ButtonEvent, MouseEventKind::Motion(button)) => button.is_some(),
+        (MouseMode::AnyEvent, MouseEventKind::Motion(_)) => true,
+    };
+    if !reported {
+        return None;
+    }
+
+    let sgr = matches!(
+        protocol.encoding,
+        MouseEncoding::Sgr | MouseEncoding::SgrPixels
+    );
+    let mut button = match event.kind {
+        MouseEventKind::Press(button) => button.code(),
+        // Only SGR reports tell which button was released
+        MouseEventKind::Release(button) if sgr => button.code(),
+        MouseEventKind::Release(_) => 3,
+        MouseEventKind::Motion(button) => 32 + button.map_or(3, MouseButton::code),
+    };
+    // X10 mode reports no modifiers
+    if protocol.mode != MouseMode::X10 {
+        button += modifier_bits(event.mods);
+    }
+
+    let (col, row) = match protocol.encoding {
+        MouseEncoding::SgrPixels => (event.x + 1, event.y + 1),
+        _ => (event.col + 1, event.row + 1),
+    };
+
+    match protocol.encoding {
+        MouseEncoding::Default => {
+            if col > MAX_DEFAULT_COORDINATE || row > MAX_DEFAULT_COORDINATE {
+                return None;
+            }
+            let mut report = b"\x1b[M".to_vec();
+            report.extend([button + 32, col as u32 + 32, row as u32 + 32].map(|byte| byte as u8));
+            Some(report)
+        }
+        MouseEncoding::Sgr | MouseEncoding::SgrPixels => {
+            let terminator = match event.kind {
+                MouseEventKind::Release(_) => 'm',
+                _ => 'M',
+            };
+            Some(format!("\x1b[<{};{};{}{}", button, col, row, terminator).into_bytes())
+        }
+        MouseEncoding::Urxvt => Some(format!("\x1b[{};{};{}M", button + 32, col, row).into_bytes()),
+    }
+}
+
+/// Get the bits modifiers add to the button number.
+fn modifier_bits(mods: ModifiersState) -> u32 {
+    let mut bits = 0;
+    if mods.shift_key() {
+        bits |= 4;
+    }
+    if mods.alt_key() {
+        bits |= 8;
+    }
+    if mods.control_key() {
+        bits |= 16;
+    }
+    bits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(kind: MouseEventKind, row: usize, col: usize) -> MouseEvent {
+        MouseEvent {
+            kind,
+            row,
+            col,
+            x: col * 10,
+            y: row * 20,
+            mods: ModifiersState::empty(),
+        }
+    }
+
+    fn protocol(mode: MouseMode, encoding: MouseEncoding) -> MouseProtocol {
+        MouseProtocol { mode, encoding }
+    }
+
+    #[test]
+    fn test_reported_events() {
+        let press = event(MouseEventKind::Press(MouseButton::Left), 0, 0);
+        let release = event(MouseEventKind::Release(MouseButton::Left), 0, 0);
+        let drag = event(MouseEventKind::Motion(Some(MouseButton::Left)), 0, 0);
+        let motion = event(MouseEventKind::Motion(None), 0, 0);
+        let reported = |mode| {
+            [press, release, drag, motion]
+                .map(|event| encode_mouse(&event, protocol(mode, MouseEncoding::Sgr)).is_some())
+        };
+
+        assert_eq!(reported(MouseMode::None), [false, false, false, false]);
+        assert_eq!(reported(MouseMode::X10), [true, false, false, false]);
+        assert_eq!(reported(MouseMode::Normal), [true, true, false, false]);
+        assert_eq!(reported(MouseMode::ButtonEvent), [true, true, true, false]);
+        assert_eq!(reported(MouseMode::AnyEvent), [true, true, true, true]);
+    }
+
+    #[test]
+    fn test_default_encoding() {
+        let normal = protocol(MouseMode::Normal, MouseEncoding::Default);
+        let press = event(MouseEventKind::Press(MouseButton::Left), 2, 4);
+        assert_eq!(encode_mouse(&press, normal).unwrap(), b"\x1b[M %#");
+        let release = event(MouseEventKind::Release(MouseButton::Right), 2, 4);
+        assert_eq!(encode_mouse(&release, normal).unwrap(), b"\x1b[M#%#");
+        let wheel = event(MouseEventKind::Press(MouseButton::WheelDown), 0, 0);
+        assert_eq!(encode_mouse(&wheel, normal).unwrap(), b"\x1b[Ma!!");
+
+        // Coordinates that do not fit in a byte are not reported
+        let far = event(MouseEventKind::Press(MouseButton::Left), 0, 300);
+        assert_eq!(encode_mouse(&far, normal), None);
+    }
+
+    #[test]
+    fn test_sgr_encoding() {
+        let sgr = protocol(MouseMode::AnyEvent, MouseEncoding::Sgr);
+        let press = event(MouseEventKind::Press(MouseButton::Middle), 2, 300);
+        assert_eq!(encode_mouse(&press, sgr).unwrap(), b"\x1b[<1;301;3M");
+        let release = event(MouseEventKind::Release(MouseButton::Middle), 2, 300);
+        assert_eq!(encode_mouse(&release, sgr).unwrap(), b"\x1b[<1;301;3m");
+        let motion = event(MouseEventKind::Motion(None), 0, 0);
+        assert_eq!(encode_mouse(&motion, sgr).unwrap(), b"\x1b[<35;1;1M");
+
+        let pixels = protocol(MouseMode::Normal, MouseEncoding::SgrPixels);
+        assert_eq!(encode_mouse(&press, pixels).unwrap(), b"\x1b[<1;3001;41M");
+    }
+
+    #[test]
+    fn test_urxvt_encoding() {
+        let urxvt = protocol(MouseMode::Normal, MouseEncoding::Urxvt);
+        let release = event(MouseEventKind::Release(MouseButton::Left), 9, 9);
+        assert_eq!(encode_mouse(&release, urxvt).unwrap(), b"\x1b[35;10;10M");
+    }
+
+    #[test]
+    fn test_modifiers() {
+        let mut press = event(MouseEventKind::Press(MouseButton::Left), 0, 0);
+        press.mods = ModifiersState::CONTROL | ModifiersState::ALT;
+        let sgr = protocol(MouseMode::Normal, MouseEncoding::Sgr);
+        assert_eq!(encode_mouse(&press, sgr).unwrap(), b"\x1b[<24;1;1M");
+
+        // X10 mode reports no modifiers
+        let x10 = protocol(MouseMode::X10, MouseEncoding::Sgr);
+        assert_eq!(encode_mouse(&press, x10).unwrap(), b"\x1b[<0;1;1M");
+    }
+}
@@ -0,0 +1,172 @@
+//! Preparing pasted text for the PTY.
+//!
+//! Applications that enable bracketed paste mode (DECSET 2004) receive
+//! pasted text between `ESC [200~` and `ESC [201~`, so they can tell it
+//! apart from typed input. Escape characters and C1 controls are removed
+//! from the text first: otherwise a clipboard containing `ESC [201~`
+//! could end the paste early and have the rest run as typed commands.
+//!
+//! Without bracketed paste, a shell runs each pasted line as soon as it
+//! arrives. Pastes that would run commands that way, or that pipe into a
+//! shell like `curl ... | sh`, can be held back until the user confirms
+//! them.
+
+/// Sent before pasted text in bracketed paste mode.
+const PASTE_START: &[u8] = b"\x1b[200~";
+
+/// Sent after pasted text in bracketed paste mode.
+const PASTE_END: &[u8] = b"\x1b[201~";
+
+/// Programs that run commands read from a pipe.
+const SHELLS: [&str; 6] = ["sh", "bash", "zsh", "dash", "ksh", "fish"];
+
+/// Number of pasted lines shown when asking for confirmation.
+const PREVIEW_LINES: usize = 5;
+
+/// Encode pasted text as the bytes to write to the PTY.
+///
+/// Without bracketed paste, line breaks are sent as carriage returns like
+/// a typed Enter.
+pub fn encode_paste(text: &str, bracketed: bool) -> Vec<u8> {
+    let text: String = text.chars().filter(|&c| !is_escape(c)).collect();
+    if bracketed {
+        [PASTE_START, text.as_bytes(), PASTE_END].concat()
+    } else {
+        text.replace("\r\n", "\r").replace('\n', "\r").into_bytes()
+    }
+}
+
+/// Check whether unbracketed pasted text should be confirmed before it
+/// is sent: it contains a line break or pipes into a shell.
+pub fn needs_confirmation(text: &str) -> bool {
+    text.contains(['\n', '\r']) || pipes_to_shell(text)
+}
+
+/// Check whether text pipes into a shell, as in `curl ... | sudo sh`.
+fn pipes_to_shell(text: &str) -> bool {
+    text.split('|').skip(1).any(|command| {
+        let mut words = command.split_whitespace();
+        let program = match words.next() {
+            Some("sudo") => words.next(),
+            program => program,
+        };
+        program
+            .map(|program| program.rsplit('/').next().unwrap_or(program))
+            .is_some_and(|program| SHELLS.contains(&program))
+    })
+}
+
+/// Get the lines of the message asking whether to paste `text`: what is
+/// wrong with it, the first lines of the text and how to answer.
+pub fn confirmation_message(text: &str) -> Vec<String> {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut message = vec![if pipes_to_shell(text) {
+        "The pasted text pipes into a shell:".to_string()
+    } else {
+        let plural = if lines.len() == 1 { "" } else { "s" };
+        format!("The pasted text has {} line{}:", lines.len(), plural)
+    }];
+    message.push(String::new());
+    for line in lines.iter().take(PREVIEW_LINES) {
+        let line = line.chars().map(|c| if c.is_control() { ' ' } else { c });
+        message.push(format!("  {}", line.collect::<String>()));
+    }
+    if lines.len() > PREVIEW_LINES {
+        message.push("  ...".to_string());
+    }
+    message.push(String::new());
+    message.push("Enter: paste   Escape: cancel".to_string());
+    message
+}
+
+/// Get the lines of the message shown when pasted text could not be
+/// sent to the shell.
+pub fn error_message(error: &str) -> Vec<String> {
+    vec![
+        "The text could not be pasted:".to_string(),
+        String::new(),
+        format!("  {}", error),
+        String::new(),
+        "Press any key to continue".to_string(),
+    ]
+}
+
+/// Check whether a character starts a control sequence: ESC or one of
+/// the C1 controls such as CSI (U+009B).
+fn is_escape(c: char) -> bool {
+    c == '\x1b' || ('\u{80}'..='\u{9f}').contains(&c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plain_paste() {
+        assert_eq!(encode_paste("ls\r\ncd /\n", false), b"ls\rcd /\r");
+    }
+
+    #[test]
+    fn test_bracketed_paste() {
+        assert_eq!(
+            encode_paste("ls\ncd /", true),
+            b"\x1b[200~ls\ncd /\x1b[201~"
+        );
+    }
+
+    #[test]
+    fn test_paste_cannot_end_bracketed_paste() {
+        assert_eq!(
+            encode_paste("a\x1b[201~rm -rf ~\n\u{9b}201~", true),
+            b"\x1b[200~a[201~rm -rf ~\n201~\x1b[201~"
+        );
+        assert_eq!(encode_paste("a\x1b[Ab", false), b"a[Ab");
+    }
+
+    #[test]
+    fn test_needs_confirmation() {
+        assert!(!needs_confirmation("ls -l"));
+        assert!(needs_confirmation("ls\n"));
+        assert!(needs_confirmation("ls\rrm x"));
+        assert!(needs_confirmation("curl -fsSL https://example.com | sh"));
+        assert!(needs_confirmation(
+            "wget -qO- example.com|sudo /bin/bash -s"
+        ));
+        assert!(!needs_confirmation("ps aux | grep shell"));
+    }
+
+    #[test]
+    fn test_confirmation_message() {
+        let message = confirmation_message("echo a\necho\tb\n");
+        assert_eq!(
+            message,
+            [
+                "The pasted text has 2 lines:",
+                "",
+                "  echo a",
+                "  echo b",
+                "",
+                "Enter: paste   Escape: cancel",
+            ]
+        );
+
+        let message = confirmation_message("1\n2\n3\n4\n5\n6\n7");
+        assert_eq!(message[6], "  5");
+        assert_eq!(message[7], "  ...");
+    }
+
+    #[test]
+    fn test_error_message() {
+        let message = error_message("input queue is full");
+        assert_eq!(
+            message,
+            [
+                "The text could not be pasted:",
+                "",
+                "  input queue is full",
+                "",
+                "Press any key to continue",
+            ]
+        );
+    }
+}
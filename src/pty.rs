@@ -1,11 +1,34 @@
 //! PTY (Pseudo-Terminal) session management for shell integration.
 //!
 //! This module handles creating a pseudo-terminal, spawning a shell process,
-//! and reading/writing its output in a separate thread.
-
-use portable_pty::{native_pty_system, CommandBuilder, Child, PtySize};
-use std::io::{self, Read, Write};
-use std::sync::{Arc, Mutex};
+//! and reading/writing its output in a separate thread. Another thread
+//! waits for the shell to exit and reports its exit status.
+//!
+//! The reader thread owns the PTY reader and only reads once `poll` says
+//! there is output. It also polls a pipe whose write end the session
+//! holds: dropping the session closes the pipe, which wakes the thread up
+//! so it can be joined right away.
+//!
+//! Input goes through a bounded queue to a writer thread, so the UI never
+//! waits for a shell that stops reading. The PTY is non-blocking: the
+//! writer waits for `poll` and writes what fits.
+//!
+//! Ctrl+S (XOFF) and Ctrl+Q (XON) are handled by the terminal driver while
+//! the shell has IXON set. Otherwise, Ctrl+S pressed while input is still
+//! queued, such as a long paste, holds that input back until Ctrl+Q.
+//! Output bytes never hold input back.
+
+use crate::config::ShellConfig;
+pub use portable_pty::PtySize;
+use portable_pty::{native_pty_system, ChildKiller, CommandBuilder, ExitStatus, MasterPty};
+use std::error::Error;
+use std::fmt;
+use std::io::{self, PipeWriter, Read, Write};
+use std::os::fd::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
 use std::thread;
 use std::time::Duration;
 
@@ -15,112 +38,219 @@ use std::time::Duration;
 /// them (e.g., write to a buffer, parse ANSI sequences, etc.).
 pub type OutputCallback = Arc<Mutex<Box<dyn Fn(&[u8]) + Send + Sync>>>;
 
+/// Callback type for learning that the shell exited, with its exit status.
+pub type ExitCallback = Box<dyn FnOnce(ExitStatus) + Send>;
+
+/// How long to wait after the shell exits for the reader thread to pass
+/// on the rest of its output before reporting the exit.
+const EXIT_DRAIN_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Number of input bytes that can wait for the writer thread.
+const INPUT_QUEUE_CAPACITY: usize = 4 * 1024 * 1024;
+
+/// Largest piece of input the writer thread takes from the queue at once,
+/// so Ctrl+S can pause a long paste part way through.
+const INPUT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// The stop and start characters of XON/XOFF flow control, sent by Ctrl+S
+/// and Ctrl+Q.
+const XOFF: u8 = 0x13;
+const XON: u8 = 0x11;
+
+/// Value of `TERM_PROGRAM` in the shell's environment.
+const TERM_PROGRAM: &str = "my-terminal";
+
+/// Shell to offer when the configured one cannot be started.
+pub const FALLBACK_SHELL: &str = "/bin/sh";
+
+/// Error from the PTY backend.
+type BoxError = Box<dyn Error + Send + Sync>;
+
+/// Reasons a PTY session cannot be started.
+#[derive(Debug)]
+pub enum PtyError {
+    /// The pseudo-terminal could not be created
+    Open(BoxError),
+    /// The shell could not be started
+    Spawn { program: String, source: BoxError },
+    /// The PTY output could not be opened for reading
+    Reader(BoxError),
+    /// The PTY could not be switched to non-blocking I/O
+    NonBlocking(io::Error),
+    /// The pipe used to stop the reader thread could not be created
+    ShutdownPipe(io::Error),
+    /// The PTY input could not be opened for writing
+    Writer(BoxError),
+    /// The thread reading PTY output could not be started
+    ReaderThread(io::Error),
+    /// The thread writing PTY input could not be started
+    WriterThread(io::Error),
+    /// The thread waiting for the shell to exit could not be started
+    WaiterThread(io::Error),
+}
+
+impl fmt::Display for PtyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PtyError::Open(e) => write!(f, "failed to open a pseudo-terminal: {}", e),
+            PtyError::Spawn { program, source } => {
+                write!(f, "failed to start {}: {}", program, source)
+            }
+            PtyError::Reader(e) => write!(f, "failed to read from the pseudo-terminal: {}", e),
+            PtyError::NonBlocking(e) => {
+                write!(f, "failed to make the pseudo-terminal non-blocking: {}", e)
+            }
+            PtyError::ShutdownPipe(e) => write!(f, "failed to create the shutdown pipe: {}", e),
+            PtyError::Writer(e) => write!(f, "failed to write to the pseudo-terminal: {}", e),
+            PtyError::ReaderThread(e) => write!(f, "failed to start the reader thread: {}", e),
+            PtyError::WriterThread(e) => write!(f, "failed to start the writer thread: {}", e),
+            PtyError::WaiterThread(e) => write!(f, "failed to start the waiter thread: {}", e),
+        }
+    }
+}
+
+impl Error for PtyError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PtyError::Open(e)
+            | PtyError::Spawn { source: e, .. }
+            | PtyError::Reader(e)
+            | PtyError::Writer(e) => Some(e.as_ref()),
+            PtyError::NonBlocking(e)
+            | PtyError::ShutdownPipe(e)
+            | PtyError::ReaderThread(e)
+            | PtyError::WriterThread(e)
+            | PtyError::WaiterThread(e) => Some(e),
+        }
+    }
+}
+
 /// PTY session that manages a shell process.
 ///
-/// The session spawns a shell (zsh) and provides access to its
+/// The session spawns a shell and provides access to its
 /// input/output streams. The reader runs in a separate thread to
 /// continuously read shell output.
 pub struct PtySession {
-    /// The child shell process (kept alive to prevent premature termination)
-    _child: Box<dyn Child>,
-    /// Reader for shell output (wrapped in Arc<Mutex<>> for thread sharing)
-    reader: Arc<Mutex<Box<dyn Read + Send>>>,
-    /// Writer for shell input (wrapped in Arc<Mutex<>> for thread safety)
-    writer: Arc<Mutex<Box<dyn Write + Send>>>,
-    /// Handle to the reader thread (stored to prevent thread leak)
-    _reader_thread: Option<thread::JoinHandle<()>>,
+    /// Stops the shell when the session is dropped
+    killer: Box<dyn ChildKiller + Send + Sync>,
+    /// Set once the waiter thread has reaped the shell; its pid may then
+    /// belong to another process, so it must not be signalled
+    reaped: Arc<AtomicBool>,
+    /// PTY master, kept to report window size changes to the shell
+    master: Box<dyn MasterPty + Send>,
+    /// Queue of shell input for the writer thread
+    input: Arc<InputQueue>,
+    /// Handle to the reader thread, joined when the session is dropped
+    reader_thread: Option<thread::JoinHandle<()>>,
+    /// Handle to the writer thread, joined when the session is dropped
+    writer_thread: Option<thread::JoinHandle<()>>,
+    /// Closing this pipe tells the reader and writer threads to stop
+    shutdown: Option<PipeWriter>,
 }
 
 impl PtySession {
-    /// Create a new PTY session with a zsh shell.
-    ///
-    /// This automatically spawns the reader thread to begin reading
-    /// shell output. Output will be printed directly to stdout.
-    ///
-    /// # Returns
-    /// A `PtySession` instance ready to read shell output.
-    ///
-    /// # Panics
-    /// Panics if:
-    /// - PTY system cannot be created
-    /// - Shell process cannot be spawned
-    /// - Reader/writer cannot be obtained
-    pub fn new() -> Self {
-        Self::with_output_callback(None)
-    }
-
     /// Create a new PTY session with a custom output callback.
     ///
     /// # Arguments
-    /// * `callback` - Optional callback to handle PTY output. If None, output
-    ///               is printed directly to stdout.
+    /// * `size` - Initial terminal size in cells and pixels
+    /// * `shell` - The program to run and how to start it
+    /// * `callback` - Callback to handle PTY output
+    /// * `on_exit` - Optional callback called with the exit status once the
+    ///   shell exits and its remaining output has been read.
     ///
     /// # Returns
     /// A `PtySession` instance ready to read shell output.
     ///
-    /// # Panics
-    /// Panics if:
-    /// - PTY system cannot be created
+    /// # Errors
+    /// Returns an error if:
+    /// - The PTY cannot be created
+    /// - Reader/writer or the shutdown pipe cannot be obtained
+    /// - The PTY cannot be made non-blocking
     /// - Shell process cannot be spawned
-    /// - Reader/writer cannot be obtained
-    pub fn with_output_callback(callback: Option<OutputCallback>) -> Self {
+    /// - The reader, writer or waiter thread cannot be started
+    pub fn with_output_callback(
+        size: PtySize,
+        shell: &ShellConfig,
+        callback: OutputCallback,
+        on_exit: Option<ExitCallback>,
+    ) -> Result<Self, PtyError> {
         log::info!("Creating PTY session");
 
         // Get the native PTY system for the current platform
         let pty_system = native_pty_system();
 
-        // Create a new PTY pair with reasonable size
-        let pty_size = PtySize {
-            rows: 24,
-            cols: 80,
-            pixel_width: 0,
-            pixel_height: 0,
-        };
-
+        // Create a new PTY pair matching the window size
         let pty_pair = pty_system
-            .openpty(pty_size)
-            .expect("Failed to create PTY pair");
+            .openpty(size)
+            .map_err(|e| PtyError::Open(e.into()))?;
 
         log::debug!("PTY pair created successfully");
 
-        // Build the command to spawn zsh
-        let mut cmd = CommandBuilder::new("zsh");
-
-        // Set up a clean environment for the shell
-        cmd.env("TERM", "xterm-256color");
+        // Split the PTY master into reader and writer
+        let mut reader = pty_pair
+            .master
+            .try_clone_reader()
+            .map_err(|e| PtyError::Reader(e.into()))?;
+        let mut writer = pty_pair
+            .master
+            .take_writer()
+            .map_err(|e| PtyError::Writer(e.into()))?;
+
+        let pty_fd = pty_pair.master.as_raw_fd().ok_or_else(|| {
+            PtyError::Reader("the pseudo-terminal has no file descriptor".into())
+        })?;
+        set_nonblocking(pty_fd).map_err(PtyError::NonBlocking)?;
+        let (shutdown_reader, shutdown) = io::pipe().map_err(PtyError::ShutdownPipe)?;
+        let writer_shutdown = shutdown_reader.try_clone().map_err(PtyError::ShutdownPipe)?;
 
-        // Spawn the shell process
-        let child = pty_pair
-            .slave
-            .spawn_command(cmd)
-            .expect("Failed to spawn zsh");
+        log::debug!("Reader and writer obtained from PTY master");
 
-        log::info!("Zsh spawned successfully");
+        // Build the command to spawn the shell
+        let cmd = shell_command(shell);
+        let program = cmd.get_argv()[0].to_string_lossy().into_owned();
 
-        // Split the PTY master into reader and writer
-        let reader = pty_pair.master.try_clone_reader().unwrap();
-        let writer = pty_pair.master.take_writer().unwrap();
+        // Spawn the shell process
+        let mut child = match pty_pair.slave.spawn_command(cmd) {
+            Ok(child) => child,
+            Err(e) => {
+                return Err(PtyError::Spawn {
+                    program,
+                    source: e.into(),
+                });
+            }
+        };
 
-        log::debug!("Reader and writer obtained from PTY master");
+        log::info!("Spawned {} successfully", program);
 
-        // Wrap both in Arc<Mutex<>> for thread-safe access
-        let reader = Arc::new(Mutex::new(reader));
-        let writer = Arc::new(Mutex::new(writer));
+        // The reader thread drops its end of this channel when it exits,
+        // which tells the waiter thread that all output has been read
+        let (reader_done, reader_finished) = mpsc::channel::<()>();
 
         // Spawn the reader thread and store the JoinHandle
-        let reader_clone = Arc::clone(&reader);
-        let handle = thread::Builder::new()
+        let spawned = thread::Builder::new()
             .name("pty-reader".to_string())
             .spawn(move || {
+                let _reader_done = reader_done;
                 log::info!("PTY reader thread started");
 
                 let mut buffer = vec![0u8; 8192];
                 let mut total_bytes = 0;
 
                 loop {
-                    // Lock the mutex to get access to the reader
-                    let mut reader_guard = reader_clone.lock().unwrap();
-                    match reader_guard.read(&mut buffer) {
+                    match wait_for(pty_fd, libc::POLLIN, shutdown_reader.as_raw_fd()) {
+                        Ok(Wakeup::Ready) => {}
+                        Ok(Wakeup::Shutdown) => {
+                            log::debug!("PTY reader asked to stop");
+                            break;
+                        }
+                        Err(e) => {
+                            log::error!("Failed to poll the PTY: {}", e);
+                            break;
+                        }
+                    }
+
+                    // Output is ready, so this does not block
+                    match reader.read(&mut buffer) {
                         Ok(0) => {
                             // EOF - shell has exited
                             log::info!("PTY reader reached EOF (shell exited)");
@@ -129,24 +259,11 @@ impl PtySession {
                         Ok(n) => {
                             total_bytes += n;
                             let data = &buffer[..n];
-                            // Release the lock before processing
-                            drop(reader_guard);
-
-                            // Use callback if provided, otherwise print to stdout
-                            if let Some(cb) = &callback {
-                                let cb = cb.lock().unwrap();
-                                cb(data);
-                            } else {
-                                // Print the output to console
-                                let output = String::from_utf8_lossy(data);
-                                print!("{}", output);
-
-                                // Ensure output is flushed immediately
-                                if let Err(e) = io::stdout().flush() {
-                                    log::error!("Failed to flush stdout: {}", e);
-                                }
-                            }
+
+                            let callback = callback.lock().unwrap();
+                            callback(data);
                         }
+                        Err(e) if is_retryable(&e) => {}
                         Err(e) => {
                             log::error!("PTY read error: {}", e);
                             break;
@@ -158,97 +275,678 @@ impl PtySession {
                     "PTY reader thread exiting (total bytes read: {})",
                     total_bytes
                 );
-            })
-            .expect("Failed to spawn reader thread");
+            });
+        let handle = match spawned {
+            Ok(handle) => handle,
+            Err(e) => {
+                // Nobody would read the shell's output
+                if let Err(e) = child.kill() {
+                    log::warn!("Failed to stop the shell: {}", e);
+                }
+                return Err(PtyError::ReaderThread(e));
+            }
+        };
+
+        // Write input from the queue in the background
+        let input = Arc::new(InputQueue::default());
+        let queued = Arc::clone(&input);
+        let spawned = thread::Builder::new()
+            .name("pty-writer".to_string())
+            .spawn(move || {
+                log::info!("PTY writer thread started");
+                let shutdown_fd = writer_shutdown.as_raw_fd();
+
+                'input: while let Some(bytes) = queued.pop() {
+                    let mut written = 0;
+                    while written < bytes.len() {
+                        match wait_for(pty_fd, libc::POLLOUT, shutdown_fd) {
+                            Ok(Wakeup::Ready) => {}
+                            Ok(Wakeup::Shutdown) => {
+                                log::debug!("PTY writer asked to stop");
+                                break 'input;
+                            }
+                            Err(e) => {
+                                log::error!("Failed to poll the PTY: {}", e);
+                                break 'input;
+                            }
+                        }
 
-        Self {
-            _child: child,
-            reader,
-            writer,
-            _reader_thread: Some(handle),
+                        // Write as much as fits; the rest waits for the
+                        // shell to read
+                        match writer.write(&bytes[written..]) {
+                            Ok(n) => written += n,
+                            Err(e) if is_retryable(&e) => {}
+                            Err(e) => {
+                                log::error!("Failed to write to PTY: {}", e);
+                                break 'input;
+                            }
+                        }
+                    }
+                }
+
+                // Writes fail from now on instead of filling the queue
+                queued.close();
+                log::info!("PTY writer thread exiting");
+            });
+        let writer_handle = match spawned {
+            Ok(handle) => handle,
+            Err(e) => {
+                // Nobody would write the shell's input
+                if let Err(e) = child.kill() {
+                    log::warn!("Failed to stop the shell: {}", e);
+                }
+                return Err(PtyError::WriterThread(e));
+            }
+        };
+
+        // Wait for the shell to exit in the background
+        let mut killer = child.clone_killer();
+        let reaped = Arc::new(AtomicBool::new(false));
+        let child_reaped = Arc::clone(&reaped);
+        let spawned = thread::Builder::new()
+            .name("pty-child-waiter".to_string())
+            .spawn(move || {
+                let status = match child.wait() {
+                    Ok(status) => status,
+                    Err(e) => {
+                        log::error!("Failed to wait for the shell: {}", e);
+                        return;
+                    }
+                };
+                child_reaped.store(true, Ordering::Release);
+                log::info!("Shell exited: {}", status);
+
+                // Let the reader pass on the last output first
+                let _ = reader_finished.recv_timeout(EXIT_DRAIN_TIMEOUT);
+                if let Some(on_exit) = on_exit {
+                    on_exit(status);
+                }
+            });
+        if let Err(e) = spawned {
+            if let Err(e) = killer.kill() {
+                log::warn!("Failed to stop the shell: {}", e);
+            }
+            return Err(PtyError::WaiterThread(e));
         }
+
+        Ok(Self {
+            killer,
+            reaped,
+            master: pty_pair.master,
+            input,
+            reader_thread: Some(handle),
+            writer_thread: Some(writer_handle),
+            shutdown: Some(shutdown),
+        })
     }
 
-    /// Write a byte to the PTY.
+    /// Tell the shell that the terminal size changed.
     ///
-    /// This sends the byte to the shell process as input.
-    pub fn write(&self, byte: u8) {
-        let mut writer = self.writer.lock().unwrap();
-        if let Err(e) = writer.write_all(&[byte]) {
-            log::error!("Failed to write to PTY: {}", e);
-        } else {
-            if let Err(e) = writer.flush() {
-                log::error!("Failed to flush PTY writer: {}", e);
-            }
+    /// The kernel sends `SIGWINCH` to the foreground process group so
+    /// full-screen programs can redraw at the new size.
+    pub fn resize(&self, size: PtySize) {
+        log::debug!("Resizing PTY to {}x{}", size.cols, size.rows);
+        if let Err(e) = self.master.resize(size) {
+            log::error!("Failed to resize PTY: {}", e);
         }
     }
 
-    /// Write a string to the PTY.
+    /// Write a byte slice to the PTY.
+    ///
+    /// This queues the bytes for the writer thread, which sends them to
+    /// the shell process as input. It never waits: if the queue has no
+    /// room because the shell has stopped reading, it returns an error.
+    ///
+    /// # Errors
+    /// Returns an error of kind:
+    /// - `WouldBlock` if the queue has no room for `bytes`
+    /// - `InvalidInput` if `bytes` is larger than the whole queue
+    /// - `BrokenPipe` if the writer thread has stopped
+    pub fn write_all(&self, bytes: &[u8]) -> io::Result<()> {
+        self.input.try_push(bytes)
+    }
+
+    /// Handle a typed Ctrl+S or Ctrl+Q as flow control if the terminal
+    /// driver does not.
     ///
-    /// This sends the string to the shell process as input.
-    pub fn write_str(&self, s: &str) {
-        let mut writer = self.writer.lock().unwrap();
-        if let Err(e) = writer.write_all(s.as_bytes()) {
-            log::error!("Failed to write to PTY: {}", e);
-        } else {
-            if let Err(e) = writer.flush() {
-                log::error!("Failed to flush PTY writer: {}", e);
+    /// With IXON off, Ctrl+S pauses queued input and Ctrl+Q resumes it.
+    /// Other keys, and Ctrl+S or Ctrl+Q when there is nothing to pause or
+    /// resume, are left for the shell so programs can still bind them.
+    ///
+    /// # Returns
+    /// `true` if the key was handled and must not be written.
+    pub fn flow_control(&self, bytes: &[u8]) -> bool {
+        let Some(fd) = self.master.as_raw_fd() else {
+            return false;
+        };
+        if ixon_enabled(fd) {
+            return false;
+        }
+        let (queued, paused) = self.input.flow_state();
+        match flow_control_key(bytes, queued, paused) {
+            Some(pause) => {
+                log::debug!("PTY input {}", if pause { "paused" } else { "resumed" });
+                self.input.set_paused(pause);
+                true
             }
+            None => false,
         }
     }
+}
 
-    /// Write a byte slice to the PTY.
-    ///
-    /// This sends the bytes to the shell process as input.
-    pub fn write_all(&self, bytes: &[u8]) {
-        let mut writer = self.writer.lock().unwrap();
-        if let Err(e) = writer.write_all(bytes) {
-            log::error!("Failed to write to PTY: {}", e);
+/// Build the command that starts the shell.
+///
+/// The program comes from the configuration, then `$SHELL`, then the
+/// passwd entry and finally `/bin/sh`. Login shells get `-l` before the
+/// configured arguments. The configured environment variables are set
+/// last so they can override the ones set here.
+fn shell_command(shell: &ShellConfig) -> CommandBuilder {
+    let program = shell
+        .program
+        .clone()
+        .unwrap_or_else(|| CommandBuilder::new_default_prog().get_shell());
+
+    let mut cmd = CommandBuilder::new(program);
+    if shell.login {
+        cmd.arg("-l");
+    }
+    cmd.args(&shell.args);
+
+    if let Some(dir) = &shell.working_directory {
+        let dir = expand_home(dir);
+        if dir.is_dir() {
+            cmd.cwd(dir);
         } else {
-            if let Err(e) = writer.flush() {
-                log::error!("Failed to flush PTY writer: {}", e);
-            }
+            log::warn!(
+                "Working directory {} does not exist, starting in the home directory",
+                dir.display()
+            );
         }
     }
 
-    /// Get a clone of the writer for direct access if needed.
-    pub fn writer(&self) -> Arc<Mutex<Box<dyn Write + Send>>> {
-        Arc::clone(&self.writer)
+    cmd.env("TERM", "xterm-256color");
+    cmd.env("COLORTERM", "truecolor");
+    cmd.env("TERM_PROGRAM", TERM_PROGRAM);
+    cmd.env("TERM_PROGRAM_VERSION", env!("CARGO_PKG_VERSION"));
+    for (key, value) in &shell.env {
+        cmd.env(key, value);
     }
+    cmd
 }
 
-// When the PtySession is dropped, wait for the reader thread to finish.
+/// Describe how the shell exited, for showing in the terminal.
+pub fn exit_message(status: &ExitStatus) -> String {
+    match status.signal() {
+        Some(signal) => format!("[process terminated by signal: {}]", signal),
+        None => format!("[process exited with code {}]", status.exit_code()),
+    }
+}
+
+/// Replace a leading `~` in a path with the home directory.
+fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), std::env::var_os("HOME")) {
+        (Ok(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
+// When the PtySession is dropped, stop the reader thread and wait for it.
 impl Drop for PtySession {
     fn drop(&mut self) {
         log::info!("PtySession dropped, cleaning up resources");
 
-        // Wait for the reader thread to finish with a timeout
-        if let Some(handle) = self._reader_thread.take() {
+        // Stop the shell unless it has exited; the waiter thread reports
+        // its exit
+        if self.reaped.load(Ordering::Acquire) {
+            log::debug!("Shell already exited");
+        } else if let Err(e) = self.killer.kill() {
+            log::debug!("Shell already stopped: {}", e);
+        }
+
+        // Closing the queue and the pipe wakes the reader and writer
+        // threads even if the shell, or a process it started, keeps the
+        // PTY open or stops reading
+        self.input.close();
+        drop(self.shutdown.take());
+
+        // The PTY master is still open here, so the threads never poll a
+        // closed file descriptor
+        if let Some(handle) = self.reader_thread.take() {
             log::debug!("Waiting for PTY reader thread to finish...");
+            if handle.join().is_err() {
+                log::error!("PTY reader thread panicked");
+            }
+        }
+        if let Some(handle) = self.writer_thread.take() {
+            log::debug!("Waiting for PTY writer thread to finish...");
+            if handle.join().is_err() {
+                log::error!("PTY writer thread panicked");
+            }
+        }
 
-            // Wait with a timeout to avoid hanging indefinitely
-            let timeout = Duration::from_secs(2);
-            let start = std::time::Instant::now();
+        // The waiter thread is not joined: it blocks until the shell is
+        // gone and only calls the exit callback
 
-            loop {
-                if handle.is_finished() {
-                    log::debug!("Reader thread has finished");
-                    let _ = handle.join();
-                    break;
-                }
+        log::debug!("PtySession cleanup complete");
+    }
+}
 
-                if start.elapsed() >= timeout {
-                    log::warn!("Reader thread did not finish within timeout, continuing cleanup");
-                    // Thread will be detached when handle is dropped
-                    break;
-                }
+/// Input waiting for the writer thread, bounded to
+/// `INPUT_QUEUE_CAPACITY` bytes.
+#[derive(Default)]
+struct InputQueue {
+    state: Mutex<InputQueueState>,
+    /// Signalled when input is added, resumed or the queue is closed
+    added: Condvar,
+}
+
+#[derive(Default)]
+struct InputQueueState {
+    /// Writes in the order they were made
+    writes: VecDeque<Vec<u8>>,
+    /// Total length of `writes`
+    len: usize,
+    /// Set when the session is dropped or the writer thread stops
+    closed: bool,
+    /// Set by Ctrl+S to hold input back until Ctrl+Q
+    paused: bool,
+}
+
+impl InputQueue {
+    /// Add `bytes` to the queue if there is room for all of them.
+    fn try_push(&self, bytes: &[u8]) -> io::Result<()> {
+        if bytes.len() > INPUT_QUEUE_CAPACITY {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} bytes of input is more than the limit of {} bytes",
+                    bytes.len(),
+                    INPUT_QUEUE_CAPACITY
+                ),
+            ));
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "the PTY writer thread has stopped",
+            ));
+        }
+        if state.len + bytes.len() > INPUT_QUEUE_CAPACITY {
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "the shell is not reading its input",
+            ));
+        }
+
+        state.len += bytes.len();
+        state
+            .writes
+            .extend(bytes.chunks(INPUT_CHUNK_SIZE).map(<[u8]>::to_vec));
+        self.added.notify_one();
+        Ok(())
+    }
+
+    /// Check whether input is queued and whether it is paused.
+    fn flow_state(&self) -> (bool, bool) {
+        let state = self.state.lock().unwrap();
+        (state.len > 0, state.paused)
+    }
+
+    /// Hold input back or let the writer thread take it again.
+    fn set_paused(&self, paused: bool) {
+        self.state.lock().unwrap().paused = paused;
+        self.added.notify_all();
+    }
+
+    /// Take the oldest write, waiting for one while the queue is empty or
+    /// paused. Returns `None` once the queue is closed.
+    fn pop(&self) -> Option<Vec<u8>> {
+        let state = self.state.lock().unwrap();
+        let mut state = self
+            .added
+            .wait_while(state, |state| {
+                !state.closed && (state.paused || state.writes.is_empty())
+            })
+            .unwrap();
+        if state.closed {
+            return None;
+        }
+        let bytes = state.writes.pop_front()?;
+        state.len -= bytes.len();
+        Some(bytes)
+    }
+
+    /// Drop the queued input and make further writes fail.
+    fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        state.writes.clear();
+        state.len = 0;
+        self.added.notify_all();
+    }
+}
+
+/// Decide what a typed key does for flow control while the terminal
+/// driver leaves it to the terminal: `Some(true)` to pause input,
+/// `Some(false)` to resume it and `None` to write the key.
+fn flow_control_key(bytes: &[u8], queued: bool, paused: bool) -> Option<bool> {
+    match bytes {
+        [XOFF] if queued && !paused => Some(true),
+        [XON] if paused => Some(false),
+        _ => None,
+    }
+}
+
+/// Check whether the terminal driver handles XON/XOFF itself (IXON).
+fn ixon_enabled(pty_fd: RawFd) -> bool {
+    // SAFETY: an all-zero termios is a valid value for tcgetattr to fill
+    let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+    // SAFETY: `termios` is a valid termios struct to write to
+    if unsafe { libc::tcgetattr(pty_fd, &mut termios) } != 0 {
+        // Leave the keys to the shell if the settings are unknown
+        return true;
+    }
+    termios.c_iflag & libc::IXON != 0
+}
+
+/// Why a PTY thread woke up.
+#[derive(Debug, PartialEq)]
+enum Wakeup {
+    /// The PTY is ready, or was closed
+    Ready,
+    /// The session is being dropped
+    Shutdown,
+}
+
+/// Block until the PTY is ready for `events` or the shutdown pipe is
+/// closed.
+fn wait_for(pty_fd: RawFd, events: libc::c_short, shutdown_fd: RawFd) -> io::Result<Wakeup> {
+    let mut fds = [
+        libc::pollfd {
+            fd: pty_fd,
+            events,
+            revents: 0,
+        },
+        libc::pollfd {
+            fd: shutdown_fd,
+            events: libc::POLLIN,
+            revents: 0,
+        },
+    ];
+    loop {
+        // SAFETY: `fds` is a valid array of `fds.len()` pollfd structs that
+        // outlives the call
+        let ready = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) };
+        if ready >= 0 {
+            break;
+        }
+        let error = io::Error::last_os_error();
+        if error.kind() != io::ErrorKind::Interrupted {
+            return Err(error);
+        }
+    }
+
+    // A closed pipe reports POLLHUP; stop even if the PTY is also ready
+    if fds[1].revents != 0 {
+        Ok(Wakeup::Shutdown)
+    } else {
+        // POLLHUP and POLLERR on the PTY are left for `read` and `write`
+        // to report
+        Ok(Wakeup::Ready)
+    }
+}
+
+/// Check whether a read or write on the non-blocking PTY only has to be
+/// tried again.
+fn is_retryable(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+    )
+}
+
+/// Make reads and writes on a file descriptor return instead of waiting.
+fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    // SAFETY: fcntl with F_GETFL and F_SETFL only reads and sets the file
+    // status flags of `fd`
+    let result = unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags < 0 {
+            flags
+        } else {
+            libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK)
+        }
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Get an output callback that ignores the output.
+    fn discard_output() -> OutputCallback {
+        Arc::new(Mutex::new(Box::new(|_| {})))
+    }
+
+    #[test]
+    fn test_shell_command() {
+        let mut shell = ShellConfig {
+            program: Some("fish".to_string()),
+            args: vec!["--private".to_string()],
+            login: true,
+            ..ShellConfig::default()
+        };
+        shell.env.insert("TERM".to_string(), "xterm".to_string());
+
+        let cmd = shell_command(&shell);
+        assert_eq!(cmd.get_argv(), &["fish", "-l", "--private"]);
+        assert_eq!(cmd.get_env("COLORTERM").unwrap(), "truecolor");
+        assert_eq!(cmd.get_env("TERM_PROGRAM").unwrap(), TERM_PROGRAM);
+        // Configured variables win
+        assert_eq!(cmd.get_env("TERM").unwrap(), "xterm");
+    }
+
+    #[test]
+    fn test_default_shell() {
+        let cmd = shell_command(&ShellConfig::default());
+        assert!(!cmd.get_argv()[0].is_empty());
+        assert_eq!(cmd.get_argv().len(), 1);
+    }
+
+    #[test]
+    fn test_exit_message() {
+        assert_eq!(
+            exit_message(&ExitStatus::with_exit_code(3)),
+            "[process exited with code 3]"
+        );
+        assert_eq!(
+            exit_message(&ExitStatus::with_signal("Killed")),
+            "[process terminated by signal: Killed]"
+        );
+    }
+
+    #[test]
+    fn test_exit_is_reported() {
+        let shell = ShellConfig {
+            program: Some(FALLBACK_SHELL.to_string()),
+            args: vec!["-c".to_string(), "exit 3".to_string()],
+            ..ShellConfig::default()
+        };
+        let (sender, receiver) = mpsc::channel();
+        let on_exit: ExitCallback = Box::new(move |status| {
+            let _ = sender.send(status);
+        });
+        let session = PtySession::with_output_callback(
+            PtySize::default(),
+            &shell,
+            discard_output(),
+            Some(on_exit),
+        )
+        .unwrap();
+        let status = receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(status.exit_code(), 3);
+
+        // Dropping the session must not signal the reaped pid
+        assert!(session.reaped.load(Ordering::Acquire));
+    }
+
+    #[test]
+    fn test_drop_does_not_wait_for_the_shell() {
+        // A shell that ignores SIGHUP keeps the PTY open after it is killed
+        let shell = ShellConfig {
+            program: Some(FALLBACK_SHELL.to_string()),
+            args: vec!["-c".to_string(), "trap '' HUP; sleep 5".to_string()],
+            ..ShellConfig::default()
+        };
+        let session =
+            PtySession::with_output_callback(PtySize::default(), &shell, discard_output(), None).unwrap();
+        let start = std::time::Instant::now();
+        drop(session);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_input_is_written() {
+        let shell = ShellConfig {
+            program: Some(FALLBACK_SHELL.to_string()),
+            args: vec!["-c".to_string(), "read line; echo \"got $line\"".to_string()],
+            ..ShellConfig::default()
+        };
+        let (sender, receiver) = mpsc::channel();
+        let output: OutputCallback = Arc::new(Mutex::new(Box::new(move |data: &[u8]| {
+            let _ = sender.send(data.to_vec());
+        })));
+        let session =
+            PtySession::with_output_callback(PtySize::default(), &shell, output, None).unwrap();
+        session.write_all(b"hello\n").unwrap();
+
+        let mut received = Vec::new();
+        while !String::from_utf8_lossy(&received).contains("got hello") {
+            let data = receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+            received.extend(data);
+        }
+    }
 
-                // Small sleep to avoid busy-waiting
-                std::thread::sleep(Duration::from_millis(50));
+    #[test]
+    fn test_write_does_not_block() {
+        // The shell never reads its input. In canonical mode the terminal
+        // driver would discard input once its buffer is full.
+        let shell = ShellConfig {
+            program: Some(FALLBACK_SHELL.to_string()),
+            args: vec!["-c".to_string(), "stty raw -echo; sleep 5".to_string()],
+            ..ShellConfig::default()
+        };
+        let session =
+            PtySession::with_output_callback(PtySize::default(), &shell, discard_output(), None).unwrap();
+        let start = std::time::Instant::now();
+        let chunk = vec![b'x'; 64 * 1024];
+        let error = loop {
+            if let Err(e) = session.write_all(&chunk) {
+                break e;
             }
+            assert!(start.elapsed() < Duration::from_secs(5));
+        };
+        assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+
+        let too_large = vec![b'x'; INPUT_QUEUE_CAPACITY + 1];
+        let error = session.write_all(&too_large).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+
+        drop(session);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_input_queue() {
+        let queue = InputQueue::default();
+        queue.try_push(b"ab").unwrap();
+        queue.try_push(&vec![0; INPUT_QUEUE_CAPACITY - 2]).unwrap();
+        let error = queue.try_push(b"c").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+
+        // Taking input off the queue makes room
+        assert_eq!(queue.pop().unwrap(), b"ab");
+        queue.try_push(b"c").unwrap();
+
+        // Large writes are taken in pieces
+        assert_eq!(queue.pop().unwrap().len(), INPUT_CHUNK_SIZE);
+
+        queue.close();
+        assert_eq!(queue.pop(), None);
+        let error = queue.try_push(b"d").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn test_paused_input() {
+        let queue = Arc::new(InputQueue::default());
+        queue.set_paused(true);
+        queue.try_push(b"ab").unwrap();
+
+        let (sender, receiver) = mpsc::channel();
+        let writer = Arc::clone(&queue);
+        thread::spawn(move || {
+            let _ = sender.send(writer.pop());
+        });
+        assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+
+        queue.set_paused(false);
+        let popped = receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(popped.unwrap(), b"ab");
+    }
+
+    #[test]
+    fn test_flow_control_key() {
+        // Ctrl+S only pauses input that is waiting to be written
+        assert_eq!(flow_control_key(&[XOFF], true, false), Some(true));
+        assert_eq!(flow_control_key(&[XOFF], false, false), None);
+        assert_eq!(flow_control_key(&[XON], true, true), Some(false));
+        assert_eq!(flow_control_key(&[XON], true, false), None);
+        assert_eq!(flow_control_key(b"s", true, false), None);
+    }
+
+    #[test]
+    fn test_binary_output_does_not_stop_input() {
+        // Output containing XOFF, even with IXOFF set, is just output
+        let script = "stty ixoff; printf 'a\\023b\\n'; read line; echo \"got $line\"";
+        let shell = ShellConfig {
+            program: Some(FALLBACK_SHELL.to_string()),
+            args: vec!["-c".to_string(), script.to_string()],
+            ..ShellConfig::default()
+        };
+        let (sender, receiver) = mpsc::channel();
+        let output: OutputCallback = Arc::new(Mutex::new(Box::new(move |data: &[u8]| {
+            let _ = sender.send(data.to_vec());
+        })));
+        let session =
+            PtySession::with_output_callback(PtySize::default(), &shell, output, None).unwrap();
+
+        let mut received = Vec::new();
+        while !received.contains(&0x13) {
+            received.extend(receiver.recv_timeout(Duration::from_secs(10)).unwrap());
         }
+        session.write_all(b"hello\n").unwrap();
+        while !String::from_utf8_lossy(&received).contains("got hello") {
+            received.extend(receiver.recv_timeout(Duration::from_secs(10)).unwrap());
+        }
+    }
 
-        // Child process will be automatically terminated when _child is dropped
-        log::debug!("PtySession cleanup complete");
+    #[test]
+    fn test_missing_shell_is_an_error() {
+        let shell = ShellConfig {
+            program: Some("/nonexistent/shell".to_string()),
+            ..ShellConfig::default()
+        };
+        match PtySession::with_output_callback(PtySize::default(), &shell, discard_output(), None) {
+            Err(PtyError::Spawn { program, .. }) => assert_eq!(program, "/nonexistent/shell"),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("a missing shell started"),
+        }
     }
 }
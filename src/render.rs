@@ -0,0 +1,512 @@
+//! Drawing the terminal grid into the window's pixel buffer.
+
+use crate::font::{FontRenderer, GlyphStyle};
+use crate::grid::{Cell, Color, Flags, Grid};
+use crate::selection::{Point, Selection};
+use crate::term::CursorShape;
+use unicode_width::UnicodeWidthChar;
+
+/// Space between the window edge and the grid, in pixels.
+pub const PADDING: usize = 8;
+
+/// Window background color (ARGB).
+pub const BACKGROUND: u32 = 0xff181818;
+
+/// Default text color (ARGB).
+const FOREGROUND: u32 = 0xffd8d8d8;
+
+/// Thickness of the underline and bar cursors and of the hollow block
+/// outline, in pixels.
+const CURSOR_THICKNESS: usize = 2;
+
+/// Background color of message boxes (ARGB).
+const MESSAGE_BACKGROUND: u32 = 0xff303030;
+
+/// Border color of message boxes (ARGB).
+const MESSAGE_BORDER: u32 = 0xffcdcd00;
+
+/// The 16 ANSI colors (RGB), normal then bright, as used by xterm.
+const ANSI_COLORS: [u32; 16] = [
+    0x000000, 0xcd0000, 0x00cd00, 0xcdcd00, 0x0000ee, 0xcd00cd, 0x00cdcd, 0xe5e5e5, 0x7f7f7f,
+    0xff0000, 0x00ff00, 0xffff00, 0x5c5cff, 0xff00ff, 0x00ffff, 0xffffff,
+];
+
+/// Intensity levels of the 6x6x6 color cube.
+const CUBE_LEVELS: [u32; 6] = [0, 95, 135, 175, 215, 255];
+
+/// A pixel buffer to draw into.
+pub struct Frame<'a> {
+    /// Pixels in ARGB format, row by row
+    pub pixels: &'a mut [u32],
+    /// Width in pixels
+    pub width: usize,
+    /// Height in pixels
+    pub height: usize,
+}
+
+impl Frame<'_> {
+    /// Fill a rectangle, clipped to the frame.
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: u32) {
+        let x_end = (x + width).min(self.width);
+        let y_end = (y + height).min(self.height);
+        for row in y.min(y_end)..y_end {
+            let start = row * self.width;
+            self.pixels[start + x.min(x_end)..start + x_end].fill(color);
+        }
+    }
+}
+
+/// Work out how many rows and columns of cells fit in a window of
+/// `width` x `height` pixels. At least one row and column is returned.
+pub fn grid_size(width: usize, height: usize, font: &FontRenderer) -> (usize, usize) {
+    let rows = height.saturating_sub(2 * PADDING) / font.char_height();
+    let cols = width.saturating_sub(2 * PADDING) / font.char_width();
+    (rows.max(1), cols.max(1))
+}
+
+/// Find the cell under a window position given in pixels.
+///
+/// Positions in the padding or beyond the grid map to the nearest cell.
+/// Returns `(row, col)`.
+pub fn cell_at(x: f64, y: f64, font: &FontRenderer, grid: &Grid) -> (usize, usize) {
+    let row = (y.max(0.0) as usize).saturating_sub(PADDING) / font.char_height();
+    let col = (x.max(0.0) as usize).saturating_sub(PADDING) / font.char_width();
+    (row.min(grid.rows - 1), col.min(grid.cols - 1))
+}
+
+/// Convert a palette index to an ARGB color.
+///
+/// 0-15 are the ANSI colors, 16-231 the 6x6x6 color cube and 232-255 a
+/// grayscale ramp.
+pub fn indexed_color(index: u8) -> u32 {
+    let rgb = match index {
+        0..=15 => ANSI_COLORS[index as usize],
+        16..=231 => {
+            let i = index as usize - 16;
+            (CUBE_LEVELS[i / 36] << 16) | (CUBE_LEVELS[(i / 6) % 6] << 8) | CUBE_LEVELS[i % 6]
+        }
+        232..=255 => {
+            let level = 8 + 10 * (index as u32 - 232);
+            (level << 16) | (level << 8) | level
+        }
+    };
+    0xff000000 | rgb
+}
+
+/// Convert a cell color to ARGB, using `default` for `Color::Default`.
+fn to_argb(color: Color, default: u32) -> u32 {
+    match color {
+        Color::Default => default,
+        Color::Indexed(index) => indexed_color(index),
+        Color::Rgb(r, g, b) => 0xff000000 | (r as u32) << 16 | (g as u32) << 8 | b as u32,
+    }
+}
+
+/// Scale the RGB channels of a color by `numerator / 3`.
+fn scale(color: u32, numerator: u32) -> u32 {
+    let channel = |shift: u32| ((color >> shift) & 0xff) * numerator / 3;
+    0xff000000 | channel(16) << 16 | channel(8) << 8 | channel(0)
+}
+
+/// Work out the foreground and background colors a cell is drawn with.
+///
+/// Bold text in one of the first 8 ANSI colors uses the bright variant,
+/// dim text is darkened, inverse swaps the two colors and hidden text is
+/// drawn in the background color.
+pub fn cell_colors(cell: &Cell) -> (u32, u32) {
+    let fg = match cell.fg {
+        Color::Indexed(index @ 0..=7) if cell.flags.contains(Flags::BOLD) => {
+            indexed_color(index + 8)
+        }
+        color => to_argb(color, FOREGROUND),
+    };
+    let bg = to_argb(cell.bg, BACKGROUND);
+
+    let fg = if cell.flags.contains(Flags::DIM) {
+        scale(fg, 2)
+    } else {
+        fg
+    };
+    let (fg, bg) = if cell.flags.contains(Flags::INVERSE) {
+        (bg, fg)
+    } else {
+        (fg, bg)
+    };
+    let fg = if cell.flags.contains(Flags::HIDDEN) {
+        bg
+    } else {
+        fg
+    };
+
+    (fg, bg)
+}
+
+/// Draw every visible cell of the grid, including scrollback lines
+/// when the view is scrolled back.
+///
+/// Cells are laid out on a fixed pitch of `char_width` x `char_height`
+/// starting at the padding offset. Rows or columns that do not fit in
+/// the frame are skipped. Selected cells are drawn with their colors
+/// swapped.
+pub fn draw_grid(
+    frame: &mut Frame,
+    font: &FontRenderer,
+    grid: &Grid,
+    selection: Option<&Selection>,
+) {
+    let (cell_width, cell_height) = (font.char_width(), font.char_height());
+
+    for row in 0..grid.rows {
+        let y = PADDING + row * cell_height;
+        if y + cell_height > frame.height {
+            break;
+        }
+
+        for (col, cell) in grid.visible_row(row).iter().enumerate() {
+            let x = PADDING + col * cell_width;
+            if x >= frame.width {
+                break;
+            }
+            if cell.flags.contains(Flags::WIDE_CHAR_SPACER) {
+                continue;
+            }
+            let width = if cell.flags.contains(Flags::WIDE_CHAR) {
+                cell_width * 2
+            } else {
+                cell_width
+            };
+            let point = Point::new(grid.visible_line(row), col);
+            if selection.is_some_and(|selection| selection.contains(point)) {
+                let mut cell = cell.clone();
+                if cell.flags.contains(Flags::INVERSE) {
+                    cell.flags.remove(Flags::INVERSE);
+                } else {
+                    cell.flags.insert(Flags::INVERSE);
+                }
+                draw_cell(frame, font, &cell, x, y, width);
+            } else {
+                draw_cell(frame, font, cell, x, y, width);
+            }
+        }
+    }
+}
+
+/// Draw the cursor over the cell it is on.
+///
+/// The cursor takes the text color of the cell. A block cursor shows the
+/// cell's character in the cell's background color; while the window is
+/// unfocused a hollow block is drawn instead of `shape`. Nothing is
+/// drawn when the cursor's row is scrolled out of view.
+pub fn draw_cursor(
+    frame: &mut Frame,
+    font: &FontRenderer,
+    grid: &Grid,
+    shape: CursorShape,
+    focused: bool,
+) {
+    let row = grid.cursor.row + grid.display_offset();
+    if row >= grid.rows {
+        return;
+    }
+    let (cell_width, cell_height) = (font.char_width(), font.char_height());
+    let y = PADDING + row * cell_height;
+    if y + cell_height > frame.height {
+        return;
+    }
+
+    let cells = grid.visible_row(row);
+    let mut col = grid.cursor.col.min(cells.len() - 1);
+    if cells[col].flags.contains(Flags::WIDE_CHAR_SPACER) && col > 0 {
+        col -= 1;
+    }
+    let cell = &cells[col];
+    let x = PADDING + col * cell_width;
+    let width = if cell.flags.contains(Flags::WIDE_CHAR) {
+        cell_width * 2
+    } else {
+        cell_width
+    };
+
+    let (fg, bg) = cell_colors(cell);
+    // Hidden text has the same colors for both; use the default text
+    // color so the cursor stays visible
+    let color = if fg == bg { FOREGROUND } else { fg };
+
+    if !focused {
+        frame.fill_rect(x, y, width, CURSOR_THICKNESS, color);
+        frame.fill_rect(
+            x,
+            y + cell_height - CURSOR_THICKNESS,
+            width,
+            CURSOR_THICKNESS,
+            color,
+        );
+        frame.fill_rect(x, y, CURSOR_THICKNESS, cell_height, color);
+        frame.fill_rect(
+            x + width - CURSOR_THICKNESS,
+            y,
+            CURSOR_THICKNESS,
+            cell_height,
+            color,
+        );
+        return;
+    }
+
+    match shape {
+        CursorShape::Block => {
+            frame.fill_rect(x, y, width, cell_height, color);
+            if cell.c != ' ' && !cell.flags.contains(Flags::HIDDEN) {
+                let glyphs = std::iter::once(cell.c).chain(cell.zerowidth.iter().copied());
+                for c in glyphs {
+                    font.render_char(
+                        c,
+                        x,
+                        y,
+                        frame.pixels,
+                        frame.width,
+                        frame.height,
+                        bg,
+                        glyph_style(cell),
+                    );
+                }
+            }
+        }
+        CursorShape::Underline => {
+            let underline_y = y + cell_height - CURSOR_THICKNESS;
+            frame.fill_rect(x, underline_y, width, CURSOR_THICKNESS, color);
+        }
+        CursorShape::Bar => frame.fill_rect(x, y, CURSOR_THICKNESS, cell_height, color),
+    }
+}
+
+/// Draw a box with lines of text in the middle of the frame, on top of
+/// whatever is drawn already.
+///
+/// Lines too long for the frame are cut off.
+pub fn draw_message_box(frame: &mut Frame, font: &FontRenderer, lines: &[String]) {
+    let (cell_width, cell_height) = (font.char_width(), font.char_height());
+    let max_cols = frame.width.saturating_sub(4 * PADDING) / cell_width;
+    let cols = lines
+        .iter()
+        .map(|line| line.chars().filter_map(|c| c.width()).sum::<usize>())
+        .max()
+        .unwrap_or(0)
+        .min(max_cols);
+
+    let width = cols * cell_width + 2 * PADDING;
+    let height = lines.len() * cell_height + 2 * PADDING;
+    let x = frame.width.saturating_sub(width) / 2;
+    let y = frame.height.saturating_sub(height) / 2;
+    frame.fill_rect(x, y, width, height, MESSAGE_BORDER);
+    frame.fill_rect(x + 1, y + 1, width - 2, height - 2, MESSAGE_BACKGROUND);
+
+    for (index, line) in lines.iter().enumerate() {
+        let y = y + PADDING + index * cell_height;
+        let mut col = 0;
+        for c in line.chars() {
+            let c_width = c.width().unwrap_or(0);
+            if col + c_width > cols {
+                break;
+            }
+            let x = x + PADDING + col * cell_width;
+            font.render_char(
+                c,
+                x,
+                y,
+                frame.pixels,
+                frame.width,
+                frame.height,
+                FOREGROUND,
+                GlyphStyle::default(),
+            );
+            col += c_width;
+        }
+    }
+}
+
+/// Get the glyph style for a cell's bold and italic attributes.
+fn glyph_style(cell: &Cell) -> GlyphStyle {
+    GlyphStyle {
+        bold: cell.flags.contains(Flags::BOLD),
+        italic: cell.flags.contains(Flags::ITALIC),
+    }
+}
+
+/// Draw a single cell whose top-left corner is at `(x, y)`.
+fn draw_cell(
+    frame: &mut Frame,
+    font: &FontRenderer,
+    cell: &Cell,
+    x: usize,
+    y: usize,
+    width: usize,
+) {
+    let cell_height = font.char_height();
+    let (fg, bg) = cell_colors(cell);
+
+    if bg != BACKGROUND {
+        frame.fill_rect(x, y, width, cell_height, bg);
+    }
+
+    if cell.flags.contains(Flags::HIDDEN) {
+        return;
+    }
+
+    if cell.c != ' ' {
+        let glyphs = std::iter::once(cell.c).chain(cell.zerowidth.iter().copied());
+        for c in glyphs {
+            font.render_char(
+                c,
+                x,
+                y,
+                frame.pixels,
+                frame.width,
+                frame.height,
+                fg,
+                glyph_style(cell),
+            );
+        }
+    }
+
+    if cell.flags.contains(Flags::UNDERLINE) {
+        let underline_y = (y + font.baseline() + 1).min(y + cell_height - 1);
+        frame.fill_rect(x, underline_y, width, 1, fg);
+    }
+    if cell.flags.contains(Flags::STRIKETHROUGH) {
+        frame.fill_rect(x, y + cell_height / 2, width, 1, fg);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_indexed_colors() {
+        assert_eq!(indexed_color(1), 0xffcd0000);
+        assert_eq!(indexed_color(16), 0xff000000);
+        assert_eq!(indexed_color(196), 0xffff0000);
+        assert_eq!(indexed_color(231), 0xffffffff);
+        assert_eq!(indexed_color(232), 0xff080808);
+        assert_eq!(indexed_color(255), 0xffeeeeee);
+    }
+
+    #[test]
+    fn test_cell_colors() {
+        let mut cell = Cell {
+            fg: Color::Rgb(1, 2, 3),
+            bg: Color::Indexed(4),
+            ..Cell::default()
+        };
+        assert_eq!(cell_colors(&cell), (0xff010203, 0xff0000ee));
+
+        cell.flags.insert(Flags::INVERSE);
+        assert_eq!(cell_colors(&cell), (0xff0000ee, 0xff010203));
+    }
+
+    #[test]
+    fn test_bold_uses_bright_colors() {
+        let mut cell = Cell {
+            fg: Color::Indexed(1),
+            ..Cell::default()
+        };
+        cell.flags.insert(Flags::BOLD);
+        assert_eq!(cell_colors(&cell).0, indexed_color(9));
+    }
+
+    #[test]
+    fn test_cell_at() {
+        let font = FontRenderer::new();
+        let grid = Grid::new(5, 10, 0);
+        let (cell_width, cell_height) = (font.char_width() as f64, font.char_height() as f64);
+        let padding = PADDING as f64;
+        assert_eq!(cell_at(0.0, 0.0, &font, &grid), (0, 0));
+        assert_eq!(
+            cell_at(
+                padding + 2.5 * cell_width,
+                padding + 1.5 * cell_height,
+                &font,
+                &grid
+            ),
+            (1, 2)
+        );
+        assert_eq!(cell_at(1e6, 1e6, &font, &grid), (4, 9));
+    }
+
+    #[test]
+    fn test_draw_cursor() {
+        let font = FontRenderer::new();
+        let (cell_width, cell_height) = (font.char_width(), font.char_height());
+        let (width, height) = (2 * PADDING + 4 * cell_width, 2 * PADDING + 2 * cell_height);
+        let mut pixels = vec![BACKGROUND; width * height];
+        let mut grid = Grid::new(2, 4, 10);
+        grid.cursor.row = 1;
+        grid.cursor.col = 2;
+
+        let pixel = |pixels: &[u32], x: usize, y: usize| pixels[y * width + x];
+        let (x, y) = (PADDING + 2 * cell_width, PADDING + cell_height);
+
+        let mut frame = Frame {
+            pixels: &mut pixels,
+            width,
+            height,
+        };
+        draw_cursor(&mut frame, &font, &grid, CursorShape::Bar, true);
+        assert_eq!(pixel(&pixels, x, y + 1), FOREGROUND);
+        assert_eq!(pixel(&pixels, x + cell_width - 1, y + 1), BACKGROUND);
+
+        // The hollow block outlines the cell but leaves its middle alone
+        pixels.fill(BACKGROUND);
+        let mut frame = Frame {
+            pixels: &mut pixels,
+            width,
+            height,
+        };
+        draw_cursor(&mut frame, &font, &grid, CursorShape::Bar, false);
+        assert_eq!(pixel(&pixels, x + cell_width - 1, y + 1), FOREGROUND);
+        assert_eq!(
+            pixel(&pixels, x + cell_width / 2, y + cell_height / 2),
+            BACKGROUND
+        );
+    }
+
+    #[test]
+    fn test_bold_and_italic_glyphs() {
+        let font = FontRenderer::new();
+        let (cell_width, cell_height) = (font.char_width(), font.char_height());
+        let draw = |flags: Flags| {
+            let (width, height) = (3 * cell_width, cell_height);
+            let mut pixels = vec![BACKGROUND; width * height];
+            let mut frame = Frame {
+                pixels: &mut pixels,
+                width,
+                height,
+            };
+            let mut cell = Cell {
+                c: 'l',
+                ..Cell::default()
+            };
+            cell.flags.insert(flags);
+            draw_cell(&mut frame, &font, &cell, cell_width, 0, cell_width);
+            pixels
+        };
+        let lit = |pixels: &[u32]| pixels.iter().filter(|&&p| p != BACKGROUND).count();
+        let first_lit = |pixels: &[u32], row: usize| {
+            let width = 3 * cell_width;
+            pixels[row * width..(row + 1) * width]
+                .iter()
+                .position(|&p| p != BACKGROUND)
+        };
+
+        let regular = draw(Flags::default());
+        let bold = draw(Flags::BOLD);
+        assert!(lit(&bold) > lit(&regular));
+
+        // Italic leans the top of the stem to the right
+        let italic = draw(Flags::ITALIC);
+        let top = font.baseline() - font.baseline() * 3 / 4;
+        let bottom = font.baseline() - 1;
+        assert_eq!(first_lit(&regular, top), first_lit(&regular, bottom));
+        assert!(first_lit(&italic, top) > first_lit(&italic, bottom));
+    }
+}
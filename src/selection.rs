@@ -0,0 +1,333 @@
+//! Text selection with the mouse.
+//!
+//! A selection is made of two points given as line numbers (see
+//! `Grid::visible_line`) and columns, so it stays on the same text while
+//! the view is scrolled and while output scrolls lines into the history.
+//! Word and line selections grow to whole words or lines as the pointer
+//! moves; block selections cover a rectangle of cells.
+
+use crate::grid::{Flags, Grid};
+
+/// Characters that end a word for double-click selection, unless
+/// configured otherwise.
+pub const DEFAULT_WORD_SEPARATORS: &str = ",│`|:\"' ()[]{}<>\t";
+
+/// A cell position in the grid and its history.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Point {
+    /// Line number
+    pub line: usize,
+    /// Column
+    pub col: usize,
+}
+
+impl Point {
+    pub fn new(line: usize, col: usize) -> Self {
+        Self { line, col }
+    }
+}
+
+/// How a selection grows as the pointer moves.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SelectionKind {
+    /// Character by character, started with a single click
+    Simple,
+    /// Whole words, started with a double click
+    Word,
+    /// Whole lines, started with a triple click
+    Line,
+    /// A rectangle of cells, started with Alt and a click
+    Block,
+}
+
+/// Selected text in the grid.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Selection {
+    kind: SelectionKind,
+    /// The cells selected by the initial click: a word for word
+    /// selection, a line for line selection and a single cell otherwise
+    anchor: (Point, Point),
+    /// Where the pointer is now
+    end: Point,
+    /// First selected cell
+    start: Point,
+    /// Last selected cell
+    last: Point,
+}
+
+impl Selection {
+    /// Start a selection at `point`.
+    pub fn new(kind: SelectionKind, point: Point, grid: &Grid, separators: &str) -> Self {
+        let anchor = expand(kind, point, grid, separators);
+        Self {
+            kind,
+            anchor,
+            end: point,
+            start: anchor.0,
+            last: anchor.1,
+        }
+    }
+
+    /// Extend the selection to the pointer at `point`.
+    pub fn update(&mut self, point: Point, grid: &Grid, separators: &str) {
+        self.end = point;
+        let (start, last) = expand(self.kind, point, grid, separators);
+        if start < self.anchor.0 {
+            (self.start, self.last) = (start, self.anchor.1);
+        } else {
+            (self.start, self.last) = (self.anchor.0, last.max(self.anchor.1));
+        }
+    }
+
+    /// Check whether nothing is selected: a simple selection where the
+    /// pointer has not left the cell it started on.
+    pub fn is_empty(&self) -> bool {
+        self.kind == SelectionKind::Simple && self.anchor.0 == self.end
+    }
+
+    /// Check whether a cell is selected.
+    pub fn contains(&self, point: Point) -> bool {
+        if self.is_empty() {
+            return false;
+        }
+        if self.kind == SelectionKind::Block {
+            let (first_line, last_line) = min_max(self.anchor.0.line, self.end.line);
+            let (first_col, last_col) = min_max(self.anchor.0.col, self.end.col);
+            return (first_line..=last_line).contains(&point.line)
+                && (first_col..=last_col).contains(&point.col);
+        }
+        self.start <= point && point <= self.last
+    }
+
+    /// Get the selected text.
+    ///
+    /// Lines end in a newline unless they continue on the next line
+    /// because of a soft wrap. Trailing blanks of each line are dropped.
+    pub fn text(&self, grid: &Grid) -> String {
+        if self.is_empty() {
+            return String::new();
+        }
+
+        let mut text = String::new();
+        for line in self.start.line..=self.last.line {
+            let Some(cells) = grid.line(line) else {
+                continue;
+            };
+            let mut segment = String::new();
+            for (col, cell) in cells.iter().enumerate() {
+                if !self.contains(Point::new(line, col))
+                    || cell.flags.contains(Flags::WIDE_CHAR_SPACER)
+                {
+                    continue;
+                }
+                segment.push(cell.c);
+                segment.extend(cell.zerowidth.iter());
+            }
+
+            let to_line_end = self.contains(Point::new(line, cells.len() - 1));
+            let soft_wrapped =
+                self.kind != SelectionKind::Block && to_line_end && grid.is_wrapped(line);
+            if soft_wrapped {
+                text.push_str(&segment);
+            } else {
+                text.push_str(segment.trim_end_matches(' '));
+                if line != self.last.line {
+                    text.push('\n');
+                }
+            }
+        }
+        text
+    }
+}
+
+/// Return two values in ascending order.
+fn min_max(a: usize, b: usize) -> (usize, usize) {
+    (a.min(b), a.max(b))
+}
+
+/// Get the cells a click at `point` selects for a kind of selection.
+fn expand(kind: SelectionKind, point: Point, grid: &Grid, separators: &str) -> (Point, Point) {
+    match kind {
+        SelectionKind::Simple | SelectionKind::Block => (point, point),
+        SelectionKind::Word => word_at(point, grid, separators),
+        SelectionKind::Line => {
+            let mut first = point.line;
+            while first > 0 && grid.is_wrapped(first - 1) {
+                first -= 1;
+            }
+            let mut last = point.line;
+            while grid.is_wrapped(last) {
+                last += 1;
+            }
+            (Point::new(first, 0), Point::new(last, grid.cols - 1))
+        }
+    }
+}
+
+/// Find the word around `point`, following soft wraps. A click on a
+/// separator selects only that character. The right half of a
+/// double-width character belongs to the character.
+fn word_at(point: Point, grid: &Grid, separators: &str) -> (Point, Point) {
+    let is_separator = |point: Point| {
+        grid.line(point.line)
+            .and_then(|cells| cells.get(point.col))
+            .is_none_or(|cell| {
+                !cell.flags.contains(Flags::WIDE_CHAR_SPACER) && separators.contains(cell.c)
+            })
+    };
+    if is_separator(point) {
+        return (point, point);
+    }
+
+    let mut start = point;
+    while let Some(previous) = previous_cell(start, grid)
+        && !is_separator(previous)
+    {
+        start = previous;
+    }
+    let mut end = point;
+    while let Some(next) = next_cell(end, grid)
+        && !is_separator(next)
+    {
+        end = next;
+    }
+    (start, end)
+}
+
+/// Get the cell before `point`, continuing on the previous line if it
+/// wraps into this one.
+fn previous_cell(point: Point, grid: &Grid) -> Option<Point> {
+    if point.col > 0 {
+        Some(Point::new(point.line, point.col - 1))
+    } else if point.line > 0 && grid.is_wrapped(point.line - 1) {
+        Some(Point::new(point.line - 1, grid.cols - 1))
+    } else {
+        None
+    }
+}
+
+/// Get the cell after `point`, continuing on the next line if this one
+/// wraps.
+fn next_cell(point: Point, grid: &Grid) -> Option<Point> {
+    if point.col + 1 < grid.cols {
+        Some(Point::new(point.line, point.col + 1))
+    } else if grid.is_wrapped(point.line) {
+        Some(Point::new(point.line + 1, 0))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grid::Cell;
+
+    /// Write text into a grid, wrapping at the right edge.
+    fn grid_with(rows: usize, cols: usize, text: &str) -> Grid {
+        let mut grid = Grid::new(rows, cols, 0);
+        for line in text.split('\n') {
+            for c in line.chars() {
+                if grid.cursor.col == cols {
+                    grid.set_wrapped(grid.cursor.row);
+                    grid.cursor.row += 1;
+                    grid.cursor.col = 0;
+                }
+                grid.put_char(c, 1, &Cell::default());
+                grid.cursor.col += 1;
+            }
+            grid.cursor.row += 1;
+            grid.cursor.col = 0;
+        }
+        grid
+    }
+
+    fn select(
+        grid: &Grid,
+        kind: SelectionKind,
+        from: (usize, usize),
+        to: (usize, usize),
+    ) -> String {
+        let mut selection = Selection::new(
+            kind,
+            Point::new(from.0, from.1),
+            grid,
+            DEFAULT_WORD_SEPARATORS,
+        );
+        selection.update(Point::new(to.0, to.1), grid, DEFAULT_WORD_SEPARATORS);
+        selection.text(grid)
+    }
+
+    #[test]
+    fn test_simple_selection() {
+        let grid = grid_with(3, 10, "hello\nworld");
+        assert_eq!(
+            select(&grid, SelectionKind::Simple, (0, 1), (1, 2)),
+            "ello\nwor"
+        );
+        // Selecting backwards gives the same text
+        assert_eq!(
+            select(&grid, SelectionKind::Simple, (1, 2), (0, 1)),
+            "ello\nwor"
+        );
+        // A click without a drag selects nothing
+        assert_eq!(select(&grid, SelectionKind::Simple, (0, 1), (0, 1)), "");
+    }
+
+    #[test]
+    fn test_soft_wrapped_lines_are_joined() {
+        let grid = grid_with(3, 4, "abcdef\ngh");
+        assert_eq!(
+            select(&grid, SelectionKind::Simple, (0, 0), (2, 3)),
+            "abcdef\ngh"
+        );
+    }
+
+    #[test]
+    fn test_word_selection() {
+        let grid = grid_with(2, 20, "ls foo/bar (baz)");
+        assert_eq!(
+            select(&grid, SelectionKind::Word, (0, 5), (0, 5)),
+            "foo/bar"
+        );
+        assert_eq!(select(&grid, SelectionKind::Word, (0, 12), (0, 12)), "baz");
+        assert_eq!(
+            select(&grid, SelectionKind::Word, (0, 0), (0, 4)),
+            "ls foo/bar"
+        );
+
+        // Separators are configurable
+        let mut selection = Selection::new(SelectionKind::Word, Point::new(0, 5), &grid, " /");
+        selection.update(Point::new(0, 5), &grid, " /");
+        assert_eq!(selection.text(&grid), "foo");
+    }
+
+    #[test]
+    fn test_word_selection_follows_wraps() {
+        let grid = grid_with(3, 4, "ab cdefg");
+        assert_eq!(select(&grid, SelectionKind::Word, (1, 1), (1, 1)), "cdefg");
+    }
+
+    #[test]
+    fn test_line_selection() {
+        let grid = grid_with(4, 4, "abcdef\ngh");
+        assert_eq!(select(&grid, SelectionKind::Line, (1, 0), (1, 0)), "abcdef");
+        assert_eq!(
+            select(&grid, SelectionKind::Line, (0, 2), (2, 0)),
+            "abcdef\ngh"
+        );
+    }
+
+    #[test]
+    fn test_block_selection() {
+        let grid = grid_with(3, 10, "abcdef\nghijkl\nmnopqr");
+        assert_eq!(
+            select(&grid, SelectionKind::Block, (0, 1), (2, 3)),
+            "bcd\nhij\nnop"
+        );
+        assert_eq!(
+            select(&grid, SelectionKind::Block, (2, 3), (0, 1)),
+            "bcd\nhij\nnop"
+        );
+    }
+}
@@ -0,0 +1,890 @@
+//! Terminal state model.
+//!
+//! This module holds the emulated screen: the grid of cells, the cursor,
+//! the scroll region and the terminal modes. It is driven by the escape
+//! sequence parser in `ansi.rs`, which translates PTY output into calls
+//! on `Terminal`.
+
+use crate::grid::{Cell, ClearMode, Color, Cursor, Flags, Grid};
+use crate::input::InputMode;
+use crate::kitty::KeyboardFlags;
+use crate::mouse::{MouseEncoding, MouseMode, MouseProtocol};
+use crate::selection::{Point, Selection, SelectionKind};
+use serde::Deserialize;
+use unicode_width::UnicodeWidthChar;
+
+/// Distance between default tab stops.
+const TAB_WIDTH: usize = 8;
+
+/// Maximum number of entries on a kitty keyboard mode stack. Pushing
+/// onto a full stack drops the oldest entry.
+const KEYBOARD_MODE_STACK_LIMIT: usize = 16;
+
+/// A character attribute set through SGR.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Attr {
+    /// Clear all attributes (SGR 0)
+    Reset,
+    /// Bold (SGR 1)
+    Bold,
+    /// Dim (SGR 2)
+    Dim,
+    /// Italic (SGR 3)
+    Italic,
+    /// Underline (SGR 4)
+    Underline,
+    /// Inverse (SGR 7)
+    Inverse,
+    /// Hidden (SGR 8)
+    Hidden,
+    /// Strikethrough (SGR 9)
+    Strikethrough,
+    /// Neither bold nor dim (SGR 22)
+    CancelBoldDim,
+    /// Not italic (SGR 23)
+    CancelItalic,
+    /// Not underlined (SGR 24)
+    CancelUnderline,
+    /// Not inverse (SGR 27)
+    CancelInverse,
+    /// Not hidden (SGR 28)
+    CancelHidden,
+    /// Not struck through (SGR 29)
+    CancelStrikethrough,
+    /// Foreground color (SGR 30-39, 90-97)
+    Foreground(Color),
+    /// Background color (SGR 40-49, 100-107)
+    Background(Color),
+}
+
+/// Shape of the text cursor.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CursorShape {
+    /// A box covering the whole cell
+    #[default]
+    Block,
+    /// A line under the cell
+    Underline,
+    /// A vertical line at the left edge of the cell
+    Bar,
+}
+
+/// Cursor appearance requested through DECSCUSR.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CursorStyle {
+    /// How the cursor is drawn
+    pub shape: CursorShape,
+    /// Whether the cursor blinks
+    pub blinking: bool,
+}
+
+/// Terminal modes toggled with SM/RM and DECSET/DECRST.
+#[derive(Debug, Clone, Copy)]
+struct TermMode {
+    /// DECAWM: wrap to the next line when printing past the last column
+    autowrap: bool,
+    /// DECOM: cursor addressing is relative to the scroll region
+    origin: bool,
+    /// IRM: printed characters shift the rest of the line right
+    insert: bool,
+    /// LNM: line feed also performs a carriage return
+    linefeed_newline: bool,
+    /// The alternate screen is shown instead of the primary screen
+    alt_screen: bool,
+    /// DECCKM: cursor keys send application sequences
+    app_cursor: bool,
+    /// DECKPAM: the keypad sends application sequences
+    app_keypad: bool,
+    /// xterm modifyOtherKeys level
+    modify_other_keys: u8,
+    /// DECTCEM: the cursor is shown
+    show_cursor: bool,
+    /// Pasted text is wrapped in `ESC [200~` and `ESC [201~`
+    bracketed_paste: bool,
+    /// Mouse events reported to the application and their format
+    mouse: MouseProtocol,
+}
+
+impl Default for TermMode {
+    fn default() -> Self {
+        Self {
+            autowrap: true,
+            origin: false,
+            insert: false,
+            linefeed_newline: false,
+            alt_screen: false,
+            app_cursor: false,
+            app_keypad: false,
+            modify_other_keys: 0,
+            show_cursor: true,
+            bracketed_paste: false,
+            mouse: MouseProtocol::default(),
+        }
+    }
+}
+
+/// The emulated terminal screen.
+///
+/// Owns the grid and all state needed to interpret control functions.
+/// The cursor lives in the grid; the terminal tracks the attributes that
+/// newly printed characters receive.
+/// Replies to queries (e.g. cursor position reports) are queued and can
+/// be collected with `take_responses` to be written back to the PTY.
+pub struct Terminal {
+    /// Screen contents and cursor
+    grid: Grid,
+    /// The screen not currently shown: the alternate screen while the
+    /// primary screen is active and vice versa. The alternate screen
+    /// keeps no scrollback.
+    inactive_grid: Grid,
+    /// Colors and flags applied to printed characters
+    pen: Cell,
+    /// Cursor and pen saved by DECSC / CSI s
+    saved_cursor: (Cursor, Cell),
+    /// Saved cursor of the inactive screen
+    inactive_saved_cursor: (Cursor, Cell),
+    /// Kitty keyboard protocol flags pushed by the application; the last
+    /// entry is in effect
+    keyboard_modes: Vec<KeyboardFlags>,
+    /// Kitty keyboard mode stack of the inactive screen
+    inactive_keyboard_modes: Vec<KeyboardFlags>,
+    /// Top row of the scroll region (inclusive)
+    scroll_top: usize,
+    /// Bottom row of the scroll region (inclusive)
+    scroll_bottom: usize,
+    /// A character was printed in the last column and the next printable
+    /// character should wrap to the next line first
+    wrap_pending: bool,
+    /// Active terminal modes
+    mode: TermMode,
+    /// Tab stop flag for each column
+    tab_stops: Vec<bool>,
+    /// Text selected with the mouse
+    selection: Option<Selection>,
+    /// Cursor style set through DECSCUSR; `None` means the user's default
+    cursor_style: Option<CursorStyle>,
+    /// Window title set through OSC 0/2
+    title: String,
+    /// Bytes to send back to the PTY in reply to queries
+    responses: Vec<u8>,
+}
+
+impl Terminal {
+    /// Create a new terminal with the specified dimensions, keeping up to
+    /// `scrollback` lines of history.
+    pub fn new(rows: usize, cols: usize, scrollback: usize) -> Self {
+        let rows = rows.max(1);
+        let cols = cols.max(1);
+
+        Self {
+            grid: Grid::new(rows, cols, scrollback),
+            inactive_grid: Grid::new(rows, cols, 0),
+            pen: Cell::default(),
+            saved_cursor: (Cursor::default(), Cell::default()),
+            inactive_saved_cursor: (Cursor::default(), Cell::default()),
+            keyboard_modes: Vec::new(),
+            inactive_keyboard_modes: Vec::new(),
+            scroll_top: 0,
+            scroll_bottom: rows - 1,
+            wrap_pending: false,
+            mode: TermMode::default(),
+            tab_stops: default_tab_stops(cols),
+            selection: None,
+            cursor_style: None,
+            title: String::new(),
+            responses: Vec::new(),
+        }
+    }
+
+    /// Get the window title requested by the application.
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    /// Take the queued replies that should be written to the PTY.
+    pub fn take_responses(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.responses)
+    }
+
+    /// Get the modes that affect keyboard input.
+    pub fn input_mode(&self) -> InputMode {
+        InputMode {
+            app_cursor: self.mode.app_cursor,
+            app_keypad: self.mode.app_keypad,
+            linefeed_newline: self.mode.linefeed_newline,
+            modify_other_keys: self.mode.modify_other_keys,
+            keyboard_flags: self.keyboard_flags(),
+        }
+    }
+
+    /// Check whether the application wants pasted text bracketed.
+    pub fn bracketed_paste(&self) -> bool {
+        self.mode.bracketed_paste
+    }
+
+    /// Get the text selection, if any.
+    pub fn selection(&self) -> Option<&Selection> {
+        self.selection.as_ref()
+    }
+
+    /// Start selecting text at `point`, replacing the current selection.
+    pub fn start_selection(&mut self, kind: SelectionKind, point: Point, separators: &str) {
+        self.selection = Some(Selection::new(kind, point, &self.grid, separators));
+    }
+
+    /// Extend the selection to `point`.
+    pub fn update_selection(&mut self, point: Point, separators: &str) {
+        if let Some(selection) = &mut self.selection {
+            selection.update(point, &self.grid, separators);
+        }
+    }
+
+    /// Get the selected text, or `None` if nothing is selected.
+    pub fn selection_text(&self) -> Option<String> {
+        let text = self.selection.as_ref()?.text(&self.grid);
+        (!text.is_empty()).then_some(text)
+    }
+
+    /// Get the mouse events the application wants reported and how.
+    pub fn mouse_protocol(&self) -> MouseProtocol {
+        self.mode.mouse
+    }
+
+    /// Get the cursor style requested by the application, or `None` if
+    /// the default style should be used.
+    pub fn cursor_style(&self) -> Option<CursorStyle> {
+        self.cursor_style
+    }
+
+    /// Check whether the cursor is shown (DECTCEM).
+    pub fn cursor_visible(&self) -> bool {
+        self.mode.show_cursor
+    }
+
+    /// Get the grid of the screen currently shown.
+    pub fn grid(&self) -> &Grid {
+        &self.grid
+    }
+
+    /// Scroll the view into the scrollback history by `lines` (positive
+    /// values scroll back).
+    pub fn scroll_display(&mut self, lines: isize) {
+        self.grid.scroll_display(lines);
+    }
+
+    /// Return the view to the bottom of the screen.
+    pub fn scroll_to_bottom(&mut self) {
+        self.grid.scroll_to_bottom();
+    }
+
+    /// Resize the terminal to `rows` x `cols`.
+    ///
+    /// Wrapped lines are reflowed to the new width. The scroll region is
+    /// reset to the full screen and the selection is cleared.
+    pub fn resize(&mut self, rows: usize, cols: usize) {
+        let rows = rows.max(1);
+        let cols = cols.max(1);
+        if rows == self.rows() && cols == self.cols() {
+            return;
+        }
+        self.selection = None;
+
+        self.grid.resize(rows, cols);
+        self.inactive_grid.resize(rows, cols);
+        self.scroll_top = 0;
+        self.scroll_bottom = rows - 1;
+
+        // The cursor follows the last character printed; a pending wrap
+        // only remains pending if that character is still at the edge
+        if self.wrap_pending && self.grid.cursor.col + 1 < cols {
+            self.grid.cursor.col += 1;
+            self.wrap_pending = false;
+        }
+
+        let old_cols = self.tab_stops.len();
+        self.tab_stops.resize(cols, false);
+        for col in old_cols..cols {
+            self.tab_stops[col] = col % TAB_WIDTH == 0;
+        }
+
+        for (saved, _) in [&mut self.saved_cursor, &mut self.inactive_saved_cursor] {
+            saved.row = saved.row.min(rows - 1);
+            saved.col = saved.col.min(cols - 1);
+        }
+    }
+
+    fn rows(&self) -> usize {
+        self.grid.rows
+    }
+
+    fn cols(&self) -> usize {
+        self.grid.cols
+    }
+
+    /// Draw a printable character at the cursor and advance it.
+    ///
+    /// Double-width characters take two cells; zero-width characters
+    /// (combining marks) are attached to the previously printed cell.
+    pub fn print(&mut self, c: char) {
+        let width = c.width().unwrap_or(0);
+        if width == 0 {
+            self.push_zerowidth(c);
+            return;
+        }
+
+        if self.wrap_pending {
+            self.wrap_pending = false;
+            self.grid.set_wrapped(self.grid.cursor.row);
+            self.carriage_return();
+            self.linefeed();
+        }
+
+        // A double-width character that does not fit on the line wraps
+        // early, or is dropped when autowrap is off
+        if width == 2 && self.grid.cursor.col + 1 >= self.cols() {
+            if !self.mode.autowrap {
+                return;
+            }
+            self.grid.set_wrapped(self.grid.cursor.row);
+            self.carriage_return();
+            self.linefeed();
+        }
+
+        if self.mode.insert {
+            self.grid.insert_blank(width, &self.pen);
+        }
+        self.grid.put_char(c, width, &self.pen);
+
+        if self.grid.cursor.col + width < self.cols() {
+            self.grid.cursor.col += width;
+        } else if self.mode.autowrap {
+            self.grid.cursor.col = self.cols() - 1;
+            self.wrap_pending = true;
+        }
+    }
+
+    /// Attach a combining mark to the character before the cursor.
+    fn push_zerowidth(&mut self, c: char) {
+        let Cursor { row, col } = self.grid.cursor;
+        if self.wrap_pending {
+            self.grid.push_zerowidth(row, col, c);
+        } else if col > 0 {
+            self.grid.push_zerowidth(row, col - 1, c);
+        }
+    }
+
+    /// Move the cursor one column to the left (BS).
+    pub fn backspace(&mut self) {
+        self.wrap_pending = false;
+        self.grid.cursor.col = self.grid.cursor.col.saturating_sub(1);
+    }
+
+    /// Move the cursor forward to the next tab stop (HT).
+    pub fn tab(&mut self, count: usize) {
+        self.wrap_pending = false;
+        for _ in 0..count {
+            let next = (self.grid.cursor.col + 1..self.cols()).find(|&col| self.tab_stops[col]);
+            self.grid.cursor.col = next.unwrap_or(self.cols() - 1);
+        }
+    }
+
+    /// Move the cursor backward to the previous tab stop (CBT).
+    pub fn back_tab(&mut self, count: usize) {
+        self.wrap_pending = false;
+        for _ in 0..count {
+            let prev = (0..self.grid.cursor.col)
+                .rev()
+                .find(|&col| self.tab_stops[col]);
+            self.grid.cursor.col = prev.unwrap_or(0);
+        }
+    }
+
+    /// Set a tab stop at the cursor column (HTS).
+    pub fn set_tab_stop(&mut self) {
+        self.tab_stops[self.grid.cursor.col] = true;
+    }
+
+    /// Clear the tab stop at the cursor column, or all of them (TBC).
+    pub fn clear_tab_stops(&mut self, all: bool) {
+        if all {
+            self.tab_stops.iter_mut().for_each(|stop| *stop = false);
+        } else {
+            self.tab_stops[self.grid.cursor.col] = false;
+        }
+    }
+
+    /// Move the cursor to the first column (CR).
+    pub fn carriage_return(&mut self) {
+        self.wrap_pending = false;
+        self.grid.cursor.col = 0;
+    }
+
+    /// Move the cursor down one line, scrolling at the bottom of the
+    /// scroll region (LF, VT, FF).
+    pub fn linefeed(&mut self) {
+        if self.mode.linefeed_newline {
+            self.carriage_return();
+        }
+        self.index();
+    }
+
+    /// Move the cursor down one line, scrolling at the bottom of the
+    /// scroll region (IND).
+    pub fn index(&mut self) {
+        self.wrap_pending = false;
+        if self.grid.cursor.row == self.scroll_bottom {
+            self.scroll_up(1);
+        } else if self.grid.cursor.row + 1 < self.rows() {
+            self.grid.cursor.row += 1;
+        }
+    }
+
+    /// Move the cursor up one line, scrolling at the top of the scroll
+    /// region (RI).
+    pub fn reverse_index(&mut self) {
+        self.wrap_pending = false;
+        if self.grid.cursor.row == self.scroll_top {
+            self.scroll_down(1);
+        } else {
+            self.grid.cursor.row = self.grid.cursor.row.saturating_sub(1);
+        }
+    }
+
+    /// Ring the bell (BEL).
+    pub fn bell(&mut self) {
+        log::debug!("Bell");
+    }
+
+    /// Move the cursor up, stopping at the top of the scroll region (CUU).
+    pub fn move_up(&mut self, count: usize) {
+        self.wrap_pending = false;
+        let top = if self.grid.cursor.row >= self.scroll_top {
+            self.scroll_top
+        } else {
+            0
+        };
+        self.grid.cursor.row = self.grid.cursor.row.saturating_sub(count).max(top);
+    }
+
+    /// Move the cursor down, stopping at the bottom of the scroll region (CUD).
+    pub fn move_down(&mut self, count: usize) {
+        self.wrap_pending = false;
+        let bottom = if self.grid.cursor.row <= self.scroll_bottom {
+            self.scroll_bottom
+        } else {
+            self.rows() - 1
+        };
+        self.grid.cursor.row = (self.grid.cursor.row + count).min(bottom);
+    }
+
+    /// Move the cursor right, stopping at the last column (CUF).
+    pub fn move_forward(&mut self, count: usize) {
+        self.wrap_pending = false;
+        self.grid.cursor.col = (self.grid.cursor.col + count).min(self.cols() - 1);
+    }
+
+    /// Move the cursor left, stopping at the first column (CUB).
+    pub fn move_backward(&mut self, count: usize) {
+        self.wrap_pending = false;
+        self.grid.cursor.col = self.grid.cursor.col.saturating_sub(count);
+    }
+
+    /// Move the cursor to an absolute position (CUP, HVP).
+    ///
+    /// The row is relative to the scroll region when origin mode is set.
+    pub fn goto(&mut self, row: usize, col: usize) {
+        self.wrap_pending = false;
+        let (top, bottom) = if self.mode.origin {
+            (self.scroll_top, self.scroll_bottom)
+        } else {
+            (0, self.rows() - 1)
+        };
+        self.grid.cursor.row = (top + row).min(bottom);
+        self.grid.cursor.col = col.min(self.cols() - 1);
+    }
+
+    /// Move the cursor to an absolute row, keeping the column (VPA).
+    pub fn goto_row(&mut self, row: usize) {
+        self.goto(row, self.grid.cursor.col);
+    }
+
+    /// Move the cursor to an absolute column, keeping the row (CHA, HPA).
+    pub fn goto_col(&mut self, col: usize) {
+        self.wrap_pending = false;
+        self.grid.cursor.col = col.min(self.cols() - 1);
+    }
+
+    /// Erase part or all of the screen (ED).
+    pub fn erase_in_display(&mut self, mode: ClearMode) {
+        self.wrap_pending = false;
+        self.grid.erase_in_display(mode, &self.pen);
+    }
+
+    /// Erase the scrollback history (ED 3).
+    pub fn clear_history(&mut self) {
+        self.grid.clear_history();
+    }
+
+    /// Erase part or all of the cursor line (EL).
+    pub fn erase_in_line(&mut self, mode: ClearMode) {
+        self.wrap_pending = false;
+        self.grid.erase_in_line(mode, &self.pen);
+    }
+
+    /// Erase characters from the cursor without moving the rest of the
+    /// line (ECH).
+    pub fn erase_chars(&mut self, count: usize) {
+        self.wrap_pending = false;
+        self.grid.erase_chars(count, &self.pen);
+    }
+
+    /// Insert blank characters at the cursor (ICH).
+    pub fn insert_blank(&mut self, count: usize) {
+        self.wrap_pending = false;
+        self.grid.insert_blank(count, &self.pen);
+    }
+
+    /// Delete characters at the cursor (DCH).
+    pub fn delete_chars(&mut self, count: usize) {
+        self.wrap_pending = false;
+        self.grid.delete_chars(count, &self.pen);
+    }
+
+    /// Insert blank lines at the cursor row (IL).
+    ///
+    /// Has no effect when the cursor is outside the scroll region.
+    pub fn insert_lines(&mut self, count: usize) {
+        self.wrap_pending = false;
+        self.grid
+            .insert_lines(count, self.scroll_top..=self.scroll_bottom, &self.pen);
+        self.grid.cursor.col = 0;
+    }
+
+    /// Delete lines at the cursor row (DL).
+    ///
+    /// Has no effect when the cursor is outside the scroll region.
+    pub fn delete_lines(&mut self, count: usize) {
+        self.wrap_pending = false;
+        self.grid
+            .delete_lines(count, self.scroll_top..=self.scroll_bottom, &self.pen);
+        self.grid.cursor.col = 0;
+    }
+
+    /// Scroll the scroll region up (SU).
+    pub fn scroll_up(&mut self, count: usize) {
+        self.grid
+            .scroll_up(self.scroll_top..=self.scroll_bottom, count, &self.pen);
+    }
+
+    /// Scroll the scroll region down (SD).
+    pub fn scroll_down(&mut self, count: usize) {
+        self.grid
+            .scroll_down(self.scroll_top..=self.scroll_bottom, count, &self.pen);
+    }
+
+    /// Set the scroll region (DECSTBM).
+    ///
+    /// `bottom` of `None` means the last row. Invalid regions are ignored.
+    /// The cursor moves to the home position.
+    pub fn set_scroll_region(&mut self, top: usize, bottom: Option<usize>) {
+        let bottom = bottom.unwrap_or(self.rows() - 1).min(self.rows() - 1);
+        if top >= bottom {
+            return;
+        }
+        self.scroll_top = top;
+        self.scroll_bottom = bottom;
+        self.goto(0, 0);
+    }
+
+    /// Save the cursor position and attributes (DECSC, SCOSC).
+    pub fn save_cursor(&mut self) {
+        self.saved_cursor = (self.grid.cursor, self.pen.clone());
+    }
+
+    /// Restore the saved cursor position and attributes (DECRC, SCORC).
+    pub fn restore_cursor(&mut self) {
+        let (cursor, pen) = self.saved_cursor.clone();
+        self.wrap_pending = false;
+        self.grid.cursor.row = cursor.row.min(self.rows() - 1);
+        self.grid.cursor.col = cursor.col.min(self.cols() - 1);
+        self.pen = pen;
+    }
+
+    /// Switch between the primary and the alternate screen.
+    ///
+    /// The cursor keeps its position; each screen has its own saved
+    /// cursor. The selection is cleared. Nothing happens if the requested
+    /// screen is already shown.
+    fn swap_screen(&mut self, alt_screen: bool) {
+        if self.mode.alt_screen == alt_screen {
+            return;
+        }
+        self.mode.alt_screen = alt_screen;
+        self.wrap_pending = false;
+        self.selection = None;
+        self.grid.scroll_to_bottom();
+        std::mem::swap(&mut self.grid, &mut self.inactive_grid);
+        std::mem::swap(&mut self.saved_cursor, &mut self.inactive_saved_cursor);
+        std::mem::swap(&mut self.keyboard_modes, &mut self.inactive_keyboard_modes);
+        self.grid.cursor = self.inactive_grid.cursor;
+    }
+
+    /// Clear the whole screen with the current background color.
+    fn clear_screen(&mut self) {
+        self.grid.erase_in_display(ClearMode::All, &self.pen);
+    }
+
+    /// Apply a character attribute to the pen (SGR).
+    pub fn set_attribute(&mut self, attr: Attr) {
+        let flags = &mut self.pen.flags;
+        match attr {
+            Attr::Reset => self.pen = Cell::default(),
+            Attr::Bold => flags.insert(Flags::BOLD),
+            Attr::Dim => flags.insert(Flags::DIM),
+            Attr::Italic => flags.insert(Flags::ITALIC),
+            Attr::Underline => flags.insert(Flags::UNDERLINE),
+            Attr::Inverse => flags.insert(Flags::INVERSE),
+            Attr::Hidden => flags.insert(Flags::HIDDEN),
+            Attr::Strikethrough => flags.insert(Flags::STRIKETHROUGH),
+            Attr::CancelBoldDim => flags.remove(Flags::BOLD | Flags::DIM),
+            Attr::CancelItalic => flags.remove(Flags::ITALIC),
+            Attr::CancelUnderline => flags.remove(Flags::UNDERLINE),
+            Attr::CancelInverse => flags.remove(Flags::INVERSE),
+            Attr::CancelHidden => flags.remove(Flags::HIDDEN),
+            Attr::CancelStrikethrough => flags.remove(Flags::STRIKETHROUGH),
+            Attr::Foreground(color) => self.pen.fg = color,
+            Attr::Background(color) => self.pen.bg = color,
+        }
+    }
+
+    /// Set or reset an ANSI mode (SM, RM).
+    pub fn set_mode(&mut self, mode: u16, enabled: bool) {
+        match mode {
+            4 => self.mode.insert = enabled,
+            20 => self.mode.linefeed_newline = enabled,
+            _ => log::debug!("Unhandled mode {} = {}", mode, enabled),
+        }
+    }
+
+    /// Set or reset a DEC private mode (DECSET, DECRST).
+    pub fn set_private_mode(&mut self, mode: u16, enabled: bool) {
+        match mode {
+            1 => self.mode.app_cursor = enabled,
+            6 => {
+                self.mode.origin = enabled;
+                self.goto(0, 0);
+            }
+            7 => self.mode.autowrap = enabled,
+            9 => self.set_mouse_mode(MouseMode::X10, enabled),
+            25 => self.mode.show_cursor = enabled,
+            66 => self.mode.app_keypad = enabled,
+            1000 => self.set_mouse_mode(MouseMode::Normal, enabled),
+            1002 => self.set_mouse_mode(MouseMode::ButtonEvent, enabled),
+            1003 => self.set_mouse_mode(MouseMode::AnyEvent, enabled),
+            1006 => self.set_mouse_encoding(MouseEncoding::Sgr, enabled),
+            1015 => self.set_mouse_encoding(MouseEncoding::Urxvt, enabled),
+            1016 => self.set_mouse_encoding(MouseEncoding::SgrPixels, enabled),
+            2004 => self.mode.bracketed_paste = enabled,
+            // Alternate screen without clearing
+            47 => self.swap_screen(enabled),
+            // Alternate screen, cleared when leaving it
+            1047 => {
+                if !enabled && self.mode.alt_screen {
+                    self.clear_screen();
+                }
+                self.swap_screen(enabled);
+            }
+            1048 if enabled => self.save_cursor(),
+            1048 => self.restore_cursor(),
+            // Save the cursor and switch to a cleared alternate screen
+            1049 if enabled => {
+                if !self.mode.alt_screen {
+                    self.save_cursor();
+                    self.swap_screen(true);
+                    self.clear_screen();
+                }
+            }
+            1049 => {
+                if self.mode.alt_screen {
+                    self.swap_screen(false);
+                    self.restore_cursor();
+                }
+            }
+            _ => log::debug!("Unhandled private mode {} = {}", mode, enabled),
+        }
+    }
+
+    /// Turn mouse reporting on or off. Turning any of the modes off stops
+    /// reporting altogether, like xterm.
+    fn set_mouse_mode(&mut self, mode: MouseMode, enabled: bool) {
+        self.mode.mouse.mode = if enabled { mode } else { MouseMode::None };
+    }
+
+    /// Select a mouse report format. Turning off the format in use
+    /// returns to the default format.
+    fn set_mouse_encoding(&mut self, encoding: MouseEncoding, enabled: bool) {
+        if enabled {
+            self.mode.mouse.encoding = encoding;
+        } else if self.mode.mouse.encoding == encoding {
+            self.mode.mouse.encoding = MouseEncoding::Default;
+        }
+    }
+
+    /// Switch the keypad between application (DECKPAM) and numeric
+    /// (DECKPNM) mode.
+    pub fn set_keypad_application_mode(&mut self, enabled: bool) {
+        self.mode.app_keypad = enabled;
+    }
+
+    /// Set the xterm modifyOtherKeys level (`CSI > 4 ; level m`). Levels
+    /// other than 1 and 2 turn it off.
+    pub fn set_modify_other_keys(&mut self, level: u16) {
+        self.mode.modify_other_keys = match level {
+            1 | 2 => level as u8,
+            _ => 0,
+        };
+    }
+
+    /// Set the cursor style (DECSCUSR, `CSI n SP q`).
+    ///
+    /// Odd values blink and even values are steady: 1-2 block, 3-4
+    /// underline and 5-6 bar. 0 returns to the default style.
+    pub fn set_cursor_style(&mut self, style: u16) {
+        let shape = match style {
+            0 => {
+                self.cursor_style = None;
+                return;
+            }
+            1 | 2 => CursorShape::Block,
+            3 | 4 => CursorShape::Underline,
+            5 | 6 => CursorShape::Bar,
+            _ => {
+                log::debug!("Unknown cursor style {}", style);
+                return;
+            }
+        };
+        self.cursor_style = Some(CursorStyle {
+            shape,
+            blinking: style % 2 == 1,
+        });
+    }
+
+    /// Get the kitty keyboard protocol flags in effect.
+    fn keyboard_flags(&self) -> KeyboardFlags {
+        self.keyboard_modes.last().copied().unwrap_or_default()
+    }
+
+    /// Push kitty keyboard protocol flags onto the stack (`CSI > flags u`).
+    pub fn push_keyboard_mode(&mut self, flags: u16) {
+        if self.keyboard_modes.len() == KEYBOARD_MODE_STACK_LIMIT {
+            self.keyboard_modes.remove(0);
+        }
+        self.keyboard_modes.push(KeyboardFlags::from_bits(flags));
+    }
+
+    /// Pop `count` entries off the kitty keyboard mode stack
+    /// (`CSI < count u`).
+    pub fn pop_keyboard_modes(&mut self, count: usize) {
+        let len = self.keyboard_modes.len();
+        self.keyboard_modes.truncate(len.saturating_sub(count));
+    }
+
+    /// Change the kitty keyboard protocol flags in effect
+    /// (`CSI = flags ; how u`).
+    ///
+    /// `how` is 1 to replace the flags, 2 to add to them and 3 to remove
+    /// them.
+    pub fn set_keyboard_mode(&mut self, flags: u16, how: u16) {
+        let current = self.keyboard_flags().bits();
+        let flags = KeyboardFlags::from_bits(flags).bits();
+        let bits = match how {
+            1 => flags,
+            2 => current | flags,
+            3 => current & !flags,
+            _ => {
+                log::debug!("Unknown keyboard mode change {}", how);
+                return;
+            }
+        };
+        let flags = KeyboardFlags::from_bits(bits as u16);
+        match self.keyboard_modes.last_mut() {
+            Some(top) => *top = flags,
+            None => self.keyboard_modes.push(flags),
+        }
+    }
+
+    /// Reply with the kitty keyboard protocol flags in effect (`CSI ? u`).
+    pub fn report_keyboard_mode(&mut self) {
+        let reply = format!("\x1b[?{}u", self.keyboard_flags().bits());
+        self.responses.extend_from_slice(reply.as_bytes());
+    }
+
+    /// Set the window title (OSC 0, OSC 2).
+    pub fn set_title(&mut self, title: &str) {
+        self.title = title.to_string();
+    }
+
+    /// Reply to a device status report request (DSR).
+    pub fn device_status(&mut self, kind: u16) {
+        match kind {
+            // Operating status: OK
+            5 => self.responses.extend_from_slice(b"\x1b[0n"),
+            // Cursor position report
+            6 => {
+                let row = if self.mode.origin {
+                    self.grid.cursor.row.saturating_sub(self.scroll_top)
+                } else {
+                    self.grid.cursor.row
+                };
+                let reply = format!("\x1b[{};{}R", row + 1, self.grid.cursor.col + 1);
+                self.responses.extend_from_slice(reply.as_bytes());
+            }
+            _ => log::debug!("Unhandled device status request {}", kind),
+        }
+    }
+
+    /// Reply to a primary device attributes request (DA1).
+    pub fn identify_terminal(&mut self) {
+        // VT220 with ANSI color support
+        self.responses.extend_from_slice(b"\x1b[?62;22c");
+    }
+
+    /// Turn off the modes a program may have left behind, e.g. before
+    /// starting a new shell after the last one exited.
+    ///
+    /// Unlike `reset`, this keeps the primary screen and its history.
+    pub fn reset_modes(&mut self) {
+        self.swap_screen(false);
+        self.mode = TermMode::default();
+        self.pen = Cell::default();
+        self.keyboard_modes.clear();
+        self.inactive_keyboard_modes.clear();
+        self.scroll_top = 0;
+        self.scroll_bottom = self.rows() - 1;
+        self.wrap_pending = false;
+        self.cursor_style = None;
+    }
+
+    /// Reset the terminal to its initial state (RIS).
+    ///
+    /// Replies that have not been collected yet are kept.
+    pub fn reset(&mut self) {
+        let responses = std::mem::take(&mut self.responses);
+        // The alternate screen keeps no history; use the primary's limit
+        let primary = if self.mode.alt_screen {
+            &self.inactive_grid
+        } else {
+            &self.grid
+        };
+        *self = Self::new(self.rows(), self.cols(), primary.max_history());
+        self.responses = responses;
+    }
+}
+
+/// Create the default tab stops (every `TAB_WIDTH` columns).
+fn default_tab_stops(cols: usize) -> Vec<bool> {
+    (0..cols)
+        .map(|col| col % TAB_WIDTH == 0 && col != 0)
+        .collect()
+}